@@ -17,10 +17,71 @@ pub struct Metric {
     /// Fraction of the CPU time in [0, 1] for each CPU
     pub cpu_fracs: Vec<f64>,
 
+    /// Stack trace samples backing the value for each CPU.
+    /// Empty if the metric is not estimated by sampling.
+    pub samples: Vec<Option<SampleStats>>,
+
     /// List of sub-metrics
     pub sub_metrics: Vec<Metric>
 }
 
+/// Stack trace samples a metric estimated by sampling is based on
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct SampleStats {
+    /// Number of samples accounted to the metric
+    pub count: u64,
+
+    /// Lower bound of the 95% confidence interval of the fraction of CPU time
+    pub ci_lower: f64,
+
+    /// Upper bound of the 95% confidence interval of the fraction of CPU time
+    pub ci_upper: f64
+}
+
+impl SampleStats {
+    /// z-score of the 95% confidence level
+    const Z: f64 = 1.96;
+
+    /// Stats of a metric accounted `count` samples out of the `total` samples
+    /// of its parent event, which took `parent_cpu_frac` of the CPU time.
+    /// The confidence interval is the Wilson score interval, which holds up
+    /// even for the small sample counts and proportions close to 0 or 1.
+    pub fn new(count: u64, total: u64, parent_cpu_frac: f64) -> Self {
+        if total == 0 {
+            return Self { count, ci_lower: 0.0, ci_upper: parent_cpu_frac };
+        }
+
+        let n = total as f64;
+        let p = count.min(total) as f64 / n;
+        let z2 = Self::Z * Self::Z;
+        let denom = 1.0 + z2 / n;
+        let center = (p + z2 / (2.0 * n)) / denom;
+        let half_width = Self::Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denom;
+
+        Self {
+            count,
+            ci_lower: (center - half_width).max(0.0) * parent_cpu_frac,
+            ci_upper: (center + half_width).min(1.0) * parent_cpu_frac
+        }
+    }
+}
+
+/// Networking syscall cost of a single process
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProcessMetric {
+    /// Process id (TGID)
+    pub pid: u32,
+
+    /// Command name of the process
+    pub comm: String,
+
+    /// Fraction of the time of a single CPU spent in TX syscalls
+    pub tx_cpu_frac: f64,
+
+    /// Fraction of the time of a single CPU spent in RX syscalls
+    pub rx_cpu_frac: f64
+}
+
 /// Wraps the top-level metrics with the total power
 /// for the networking stack.
 #[derive(Serialize, Deserialize)]
@@ -35,31 +96,44 @@ pub struct MetricsWrapper {
     /// Fraction of the CPU time spent by the user-space tool
     pub user_space_overhead: f64,
 
+    /// Number of times NET_RX or NET_TX softirqs were deferred to ksoftirqd
+    /// since the previous update, over all CPUs
+    pub softirq_deferrals: u64,
+
+    /// Number of stack trace samples dropped since the
+    /// previous update because the buffer was full
+    pub dropped_samples: u64,
+
+    /// Why the breakdowns of the stack traces are unavailable, e.g. because
+    /// the kernel symbol addresses are hidden. None if they are available.
+    pub breakdowns_unavailable: Option<String>,
+
     /// Number of CPUs
     pub num_possible_cpus: usize,
 
     /// Metrics acquired from /proc/stat for validation
-    pub procfs_metrics: Vec<f64>
+    pub procfs_metrics: Vec<f64>,
+
+    /// Per-cgroup metrics.
+    /// Each entry is named after the path of a cgroup, and
+    /// holds the events accounted to it as sub-metrics.
+    pub cgroup_metrics: Vec<Metric>,
+
+    /// Processes with the highest networking syscall cost,
+    /// sorted by decreasing total cost
+    pub top_processes: Vec<ProcessMetric>,
+
+    /// Per-interface metrics.
+    /// Each entry is named after an event, and holds the
+    /// breakdown of that event for each network device as
+    /// "<event>/<interface>/..." sub-metrics.
+    pub interface_metrics: Vec<Metric>
 }
 
 impl MetricsWrapper {
-    /// Serialize this wrapper into a MessagePack buffer from the raw parts
-    pub fn to_mp(
-        top_level_metrics: &[Metric],
-        net_power_w: Option<f64>,
-        user_space_overhead: f64,
-        num_possible_cpus: usize,
-        procfs_metrics: Vec<f64>
-    ) -> Vec<u8> {
-        let wrapper = Self {
-            top_level_metrics: top_level_metrics.to_vec(),
-            net_power_w,
-            user_space_overhead,
-            num_possible_cpus,
-            procfs_metrics
-        };
-
-        rmp_serde::to_vec(&wrapper).unwrap()
+    /// Serialize this wrapper into a MessagePack buffer
+    pub fn to_mp(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
     }
 
     /// Deserialize from a MessagePack slice
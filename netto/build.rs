@@ -16,7 +16,18 @@ fn main() {
     bindgen::Builder::default()
         .header(SRC_H)
         .allowlist_type("per_cpu_data")
+        .allowlist_type("per_cgroup_data")
+        .allowlist_type("per_process_data")
         .allowlist_type("event_types")
+        .allowlist_type("ksym_range")
+        .allowlist_type("ksym_actions")
+        .allowlist_type("event_breakdown")
+        .allowlist_type("category_counts")
+        .allowlist_var("STACK_TRACE_.*")
+        .allowlist_var("KSYM_.*")
+        .allowlist_var("BREAKDOWNS_MAX")
+        .allowlist_var("MARKS_MAX")
+        .allowlist_var("NF_HOOK_STATES_MAX")
         .generate()
         .unwrap()
         .write_to_file("src/common.rs")
@@ -1,10 +1,18 @@
 use std::collections::HashSet;
 use actix::{Addr, Actor, Context, Handler};
-use metrics_common::{Metric, MetricsWrapper};
-use super::{websocket_client::WebsocketClient, MetricUpdate, SubmitUpdate, EncodedUpdate, ClientConnected, ClientDisconnected, file_logger::FileLogger, prometheus_logger::PrometheusLogger};
+use metrics_common::{Metric, MetricsWrapper, ProcessMetric};
+use super::{websocket_client::WebsocketClient, MetricUpdate, SubmitUpdate, TopProcessesUpdate, EncodedUpdate, ClientConnected, ClientDisconnected, file_logger::FileLogger, prometheus_logger::PrometheusLogger};
 
 pub struct MetricsCollector {
     metrics_root: Metric,
+    /// Root of the per-cgroup metrics, with one sub-metric for each cgroup
+    cgroups_root: Metric,
+    /// Root of the per-interface metrics
+    interfaces_root: Metric,
+    /// Names of the metrics under `metrics_root` with netfilter chains as sub-metrics
+    nf_chain_parents: HashSet<String>,
+    /// Latest processes with the highest syscall cost
+    top_processes: Vec<ProcessMetric>,
     clients: HashSet<Addr<WebsocketClient>>,
     file_logger: Option<Addr<FileLogger>>,
     prometheus_logger: Option<Addr<PrometheusLogger>>,
@@ -19,27 +27,32 @@ impl Handler<MetricUpdate> for MetricsCollector {
     type Result = ();
 
     fn handle(&mut self, msg: MetricUpdate, _: &mut Self::Context) -> Self::Result {
-        let mut target = &mut self.metrics_root;
-
-        for segment in msg.name.split('/') {
-            let sub_metric_index = target.sub_metrics
-                .iter()
-                .enumerate()
-                .find_map(|(i, e)| (e.name == segment).then_some(i))
-                .unwrap_or_else(|| {
-                    target.sub_metrics.push(Metric {
-                        name: segment.to_string(),
-                        cpu_fracs: vec![],
-                        sub_metrics: vec![]
-                    });
-                    target.sub_metrics.len() - 1
-                });
-            
-            target = &mut target.sub_metrics[sub_metric_index];
-        }
+        let target = if let Some(cgroup) = &msg.cgroup {
+            // Cgroup paths contain slashes themselves, so they make up a single segment
+            Self::get_or_insert_metric(&mut self.cgroups_root, std::iter::once(cgroup.as_str()).chain(msg.name.split('/')))
+        } else if let Some(interface) = &msg.interface {
+            // Build a "<event>/<interface>/..." subtree
+            let mut segments = msg.name.split('/');
+            let event = segments.next();
+            Self::get_or_insert_metric(&mut self.interfaces_root, event.into_iter().chain(std::iter::once(interface.as_str())).chain(segments))
+        } else if let Some((table, chain)) = &msg.nf_chain {
+            // Build a "<name>/<table>/<chain>" subtree, with the names as single segments,
+            // or "<name>/Other" for the chains not reported individually
+            let nf_chain = [table.as_str(), chain.as_str()].into_iter().filter(|s| !s.is_empty());
+            if !self.nf_chain_parents.contains(&msg.name) {
+                self.nf_chain_parents.insert(msg.name.clone());
+            }
+            Self::get_or_insert_metric(&mut self.metrics_root, msg.name.split('/').chain(nf_chain))
+        } else {
+            Self::get_or_insert_metric(&mut self.metrics_root, msg.name.split('/'))
+        };
 
         target.cpu_fracs.resize(self.num_possible_cpus, 0.0);
         target.cpu_fracs[msg.cpuid] = msg.cpu_frac;
+        if msg.samples.is_some() {
+            target.samples.resize(self.num_possible_cpus, None);
+            target.samples[msg.cpuid] = msg.samples;
+        }
 
         // Relay msg to prometheus logger
         if let Some(l) = &self.prometheus_logger {
@@ -48,6 +61,19 @@ impl Handler<MetricUpdate> for MetricsCollector {
     }
 }
 
+impl Handler<TopProcessesUpdate> for MetricsCollector {
+    type Result = ();
+
+    fn handle(&mut self, msg: TopProcessesUpdate, _: &mut Self::Context) -> Self::Result {
+        // Relay msg to prometheus logger
+        if let Some(l) = &self.prometheus_logger {
+            l.do_send(msg.clone());
+        }
+
+        self.top_processes = msg.processes;
+    }
+}
+
 impl Handler<SubmitUpdate> for MetricsCollector {
     type Result = ();
 
@@ -58,13 +84,19 @@ impl Handler<SubmitUpdate> for MetricsCollector {
         }
         
         if !self.clients.is_empty() || self.file_logger.is_some() {
-            let mp = MetricsWrapper::to_mp(
-                &self.metrics_root.sub_metrics,
-                msg.net_power_w,
-                msg.user_space_overhead,
-                self.num_possible_cpus,
-                msg.procfs_metrics
-            );
+            let mp = MetricsWrapper {
+                top_level_metrics: self.metrics_root.sub_metrics.clone(),
+                net_power_w: msg.net_power_w,
+                user_space_overhead: msg.user_space_overhead,
+                softirq_deferrals: msg.softirq_deferrals,
+                dropped_samples: msg.dropped_samples,
+                breakdowns_unavailable: msg.breakdowns_unavailable,
+                num_possible_cpus: self.num_possible_cpus,
+                procfs_metrics: msg.procfs_metrics,
+                cgroup_metrics: self.cgroups_root.sub_metrics.clone(),
+                top_processes: self.top_processes.clone(),
+                interface_metrics: self.interfaces_root.sub_metrics.clone()
+            }.to_mp();
 
             for addr in &self.clients {
                 addr.do_send(EncodedUpdate { inner: mp.clone() });
@@ -74,6 +106,13 @@ impl Handler<SubmitUpdate> for MetricsCollector {
                 logger.do_send(EncodedUpdate { inner: mp });
             }
         }
+
+        // Only keep the cgroups, interfaces and netfilter chains that are updated in the next cycle
+        self.cgroups_root.sub_metrics.clear();
+        self.interfaces_root.sub_metrics.clear();
+        for name in self.nf_chain_parents.drain() {
+            Self::get_or_insert_metric(&mut self.metrics_root, name.split('/')).sub_metrics.clear();
+        }
     }
 }
 
@@ -103,12 +142,53 @@ impl MetricsCollector {
             metrics_root: Metric {
                 name: "/".to_string(),
                 cpu_fracs: vec![],
+                samples: vec![],
+                sub_metrics: vec![]
+            },
+            cgroups_root: Metric {
+                name: "/".to_string(),
+                cpu_fracs: vec![],
+                samples: vec![],
+                sub_metrics: vec![]
+            },
+            interfaces_root: Metric {
+                name: "/".to_string(),
+                cpu_fracs: vec![],
+                samples: vec![],
                 sub_metrics: vec![]
             },
+            nf_chain_parents: HashSet::new(),
+            top_processes: vec![],
             clients: HashSet::new(),
             file_logger,
             prometheus_logger,
             num_possible_cpus
         }
     }
+
+    /// Walk down the metrics tree from `root` following the segments of a
+    /// hierarchical name, creating any missing metric along the way
+    fn get_or_insert_metric<'a, 'b>(root: &'a mut Metric, segments: impl Iterator<Item = &'b str>) -> &'a mut Metric {
+        let mut target = root;
+
+        for segment in segments {
+            let sub_metric_index = target.sub_metrics
+                .iter()
+                .enumerate()
+                .find_map(|(i, e)| (e.name == segment).then_some(i))
+                .unwrap_or_else(|| {
+                    target.sub_metrics.push(Metric {
+                        name: segment.to_string(),
+                        cpu_fracs: vec![],
+                        samples: vec![],
+                        sub_metrics: vec![]
+                    });
+                    target.sub_metrics.len() - 1
+                });
+            
+            target = &mut target.sub_metrics[sub_metric_index];
+        }
+
+        target
+    }
 }
@@ -4,7 +4,9 @@ pub mod websocket_client;
 pub mod file_logger;
 pub mod prometheus_logger;
 
+use std::borrow::Cow;
 use actix::{Message, Addr};
+use metrics_common::{ProcessMetric, SampleStats};
 use self::websocket_client::WebsocketClient;
 
 /// Signal new client connected to the `MetricsCollector` actor
@@ -28,13 +30,38 @@ struct ClientDisconnected {
 struct MetricUpdate {
     /// This is the hierarchical name of the metric.
     /// For example, "RX softirq/Bridging".
-    name: &'static str,
+    name: Cow<'static, str>,
 
     /// CPU index this metric update is for
     cpuid: usize,
 
+    /// Path of the cgroup this metric update is for,
+    /// or None if the update is not bound to any cgroup
+    cgroup: Option<String>,
+
+    /// Name of the network device this metric update is for,
+    /// or None if the update is not bound to any device
+    interface: Option<String>,
+
+    /// Names of the netfilter table and chain this metric update is for,
+    /// or None if the update is not bound to any chain
+    nf_chain: Option<(String, String)>,
+
     /// Fraction of CPU time in the [0, 1] range
-    cpu_frac: f64
+    cpu_frac: f64,
+
+    /// Stack trace samples `cpu_frac` is estimated from,
+    /// or None if the metric is measured directly
+    samples: Option<SampleStats>
+}
+
+/// Update of the processes with the highest networking syscall
+/// cost in the last period, from the `TraceAnalyzer` actor.
+#[derive(Message, Clone)]
+#[rtype("()")]
+struct TopProcessesUpdate {
+    /// At most `--top-processes` processes, sorted by decreasing total cost
+    processes: Vec<ProcessMetric>
 }
 
 /// Used to trigger the `MetricsCollector` to submit the update
@@ -50,6 +77,18 @@ struct SubmitUpdate {
     /// Fraction of the CPU time spent by the user-space tool
     user_space_overhead: f64,
 
+    /// Number of times softirqs were deferred to ksoftirqd
+    /// since the previous update, over all CPUs
+    softirq_deferrals: u64,
+
+    /// Number of stack trace samples dropped since the
+    /// previous update because the buffer was full
+    dropped_samples: u64,
+
+    /// Why the breakdowns of the stack traces are unavailable,
+    /// None if they are available
+    breakdowns_unavailable: Option<String>,
+
     /// Metrics acquired from /proc/stat for validation
     procfs_metrics: Vec<f64>
 }
@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 use actix::{Actor, Context, Handler};
 use actix_web::web;
-use prometheus::{Registry, GaugeVec, Gauge, Opts, TextEncoder};
+use prometheus::{Registry, GaugeVec, Gauge, IntCounter, Opts, TextEncoder};
 use tokio::sync::watch::{Sender, Receiver};
-use super::{MetricUpdate, SubmitUpdate};
+use super::{MetricUpdate, SubmitUpdate, TopProcessesUpdate};
 
 #[actix_web::get("/")]
 async fn prometheus_log_get(
@@ -17,9 +17,20 @@ pub struct PrometheusLogger {
     encoder: TextEncoder,
     
     metrics: HashMap<String, GaugeVec>,
+    cgroup_metrics: HashMap<String, GaugeVec>,
+    interface_metrics: HashMap<String, GaugeVec>,
+    nf_chain_metrics: HashMap<String, GaugeVec>,
     procfs_metrics: GaugeVec,
+    metric_samples: GaugeVec,
+    metric_ci_lower: GaugeVec,
+    metric_ci_upper: GaugeVec,
+    process_tx_syscalls: GaugeVec,
+    process_rx_syscalls: GaugeVec,
     net_power_w: Gauge,
     user_space_overhead: Gauge,
+    softirq_deferrals: IntCounter,
+    dropped_samples: IntCounter,
+    breakdowns_available: Gauge,
 
     watch_sender: Sender<String>
 }
@@ -31,16 +42,81 @@ impl Handler<MetricUpdate> for PrometheusLogger {
         let name = msg.name
             .to_ascii_lowercase()
             .replace(' ', "_")
-            .replace('/', "__");
+            .replace('/', "__")
+            .replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+
+        // Sample counts and confidence intervals of the metrics estimated by sampling
+        if let Some(samples) = &msg.samples {
+            let metric = match &msg.nf_chain {
+                Some((table, chain)) if chain.is_empty() => format!("{name}__{table}"),
+                Some((table, chain)) => format!("{name}__{table}__{chain}"),
+                None => name.clone()
+            };
+            let labels = [metric.as_str(), &format!("{}", msg.cpuid), msg.interface.as_deref().unwrap_or("")];
+            self.metric_samples.with_label_values(&labels).set(samples.count as f64);
+            self.metric_ci_lower.with_label_values(&labels).set(samples.ci_lower);
+            self.metric_ci_upper.with_label_values(&labels).set(samples.ci_upper);
+        }
         
-        self.metrics.entry(name.clone())
-            .or_insert_with(|| {
-                let g = GaugeVec::new(Opts::new(name, msg.name), &["cpu"]).unwrap();
-                self.registry.register(Box::new(g.clone())).unwrap();
-                g
-            })
-            .with_label_values(&[&format!("{}", msg.cpuid)])
-            .set(msg.cpu_frac);
+        if let Some(cgroup) = &msg.cgroup {
+            let name = format!("cgroup__{name}");
+            
+            self.cgroup_metrics.entry(name.clone())
+                .or_insert_with(|| {
+                    let g = GaugeVec::new(Opts::new(name, msg.name.as_ref()), &["cpu", "cgroup"]).unwrap();
+                    self.registry.register(Box::new(g.clone())).unwrap();
+                    g
+                })
+                .with_label_values(&[&format!("{}", msg.cpuid), cgroup])
+                .set(msg.cpu_frac);
+        } else if let Some(interface) = &msg.interface {
+            let name = format!("interface__{name}");
+            
+            self.interface_metrics.entry(name.clone())
+                .or_insert_with(|| {
+                    let g = GaugeVec::new(Opts::new(name, msg.name.as_ref()), &["cpu", "interface"]).unwrap();
+                    self.registry.register(Box::new(g.clone())).unwrap();
+                    g
+                })
+                .with_label_values(&[&format!("{}", msg.cpuid), interface])
+                .set(msg.cpu_frac);
+        } else if let Some((table, chain)) = &msg.nf_chain {
+            let name = format!("nf_chain__{name}");
+
+            self.nf_chain_metrics.entry(name.clone())
+                .or_insert_with(|| {
+                    let g = GaugeVec::new(Opts::new(name, msg.name.as_ref()), &["cpu", "table", "chain"]).unwrap();
+                    self.registry.register(Box::new(g.clone())).unwrap();
+                    g
+                })
+                .with_label_values(&[&format!("{}", msg.cpuid), table, chain])
+                .set(msg.cpu_frac);
+        } else {
+            self.metrics.entry(name.clone())
+                .or_insert_with(|| {
+                    let g = GaugeVec::new(Opts::new(name, msg.name.as_ref()), &["cpu"]).unwrap();
+                    self.registry.register(Box::new(g.clone())).unwrap();
+                    g
+                })
+                .with_label_values(&[&format!("{}", msg.cpuid)])
+                .set(msg.cpu_frac);
+        }
+    }
+}
+
+impl Handler<TopProcessesUpdate> for PrometheusLogger {
+    type Result = ();
+
+    fn handle(&mut self, msg: TopProcessesUpdate, _ctx: &mut Self::Context) -> Self::Result {
+        // Only export the current top processes to keep the cardinality bounded
+        self.process_tx_syscalls.reset();
+        self.process_rx_syscalls.reset();
+
+        for p in msg.processes {
+            let pid = format!("{}", p.pid);
+            self.process_tx_syscalls.with_label_values(&[&pid, &p.comm]).set(p.tx_cpu_frac);
+            self.process_rx_syscalls.with_label_values(&[&pid, &p.comm]).set(p.rx_cpu_frac);
+        }
     }
 }
 
@@ -53,11 +129,22 @@ impl Handler<SubmitUpdate> for PrometheusLogger {
         }
         self.net_power_w.set(msg.net_power_w.unwrap_or(-1.0));
         self.user_space_overhead.set(msg.user_space_overhead);
+        self.softirq_deferrals.inc_by(msg.softirq_deferrals);
+        self.dropped_samples.inc_by(msg.dropped_samples);
+        self.breakdowns_available.set(if msg.breakdowns_unavailable.is_some() { 0.0 } else { 1.0 });
 
         self.watch_sender.send_modify(|buf| {
             buf.clear();
             let _ = self.encoder.encode_utf8(&self.registry.gather(), buf);
         });
+
+        // Drop the series of cgroups, interfaces and netfilter chains that are not updated in the next cycle
+        for g in self.cgroup_metrics.values().chain(self.interface_metrics.values()).chain(self.nf_chain_metrics.values()) {
+            g.reset();
+        }
+        for g in [&self.metric_samples, &self.metric_ci_lower, &self.metric_ci_upper] {
+            g.reset();
+        }
     }
 }
 
@@ -67,10 +154,33 @@ impl PrometheusLogger {
         let encoder = TextEncoder::new();
         
         let metrics = HashMap::new();
+        let cgroup_metrics = HashMap::new();
+        let interface_metrics = HashMap::new();
+        let nf_chain_metrics = HashMap::new();
         let procfs_metrics = GaugeVec::new(Opts::new(
             "procfs_metric",
             "Collection of overall CPU metrics from /proc/stat"
         ), &["index"])?;
+        let metric_samples = GaugeVec::new(Opts::new(
+            "metric_samples",
+            "Number of stack trace samples each metric estimated by sampling is based on"
+        ), &["metric", "cpu", "interface"])?;
+        let metric_ci_lower = GaugeVec::new(Opts::new(
+            "metric_ci_lower",
+            "Lower bound of the 95% confidence interval of each metric estimated by sampling"
+        ), &["metric", "cpu", "interface"])?;
+        let metric_ci_upper = GaugeVec::new(Opts::new(
+            "metric_ci_upper",
+            "Upper bound of the 95% confidence interval of each metric estimated by sampling"
+        ), &["metric", "cpu", "interface"])?;
+        let process_tx_syscalls = GaugeVec::new(Opts::new(
+            "process_tx_syscalls",
+            "Fraction of the time of a single CPU spent in TX syscalls by each of the top processes"
+        ), &["pid", "comm"])?;
+        let process_rx_syscalls = GaugeVec::new(Opts::new(
+            "process_rx_syscalls",
+            "Fraction of the time of a single CPU spent in RX syscalls by each of the top processes"
+        ), &["pid", "comm"])?;
         let net_power_w = Gauge::new(
             "net_power",
             "Total amount of power (in W) consumed by networking. Negative if unavailable"
@@ -79,18 +189,49 @@ impl PrometheusLogger {
             "user_space_overhead",
             "Fraction of CPU time used by Netto in the userspace to analyze stack traces"
         )?;
+        let softirq_deferrals = IntCounter::new(
+            "softirq_deferrals_total",
+            "Total number of times NET_RX or NET_TX softirqs were deferred to ksoftirqd"
+        )?;
+        let dropped_samples = IntCounter::new(
+            "dropped_samples_total",
+            "Total number of stack trace samples dropped because the buffer was full"
+        )?;
+        let breakdowns_available = Gauge::new(
+            "breakdowns_available",
+            "Whether the breakdowns of the stack traces are available (1) or not (0), e.g. because the kernel symbol addresses are hidden"
+        )?;
 
         registry.register(Box::new(procfs_metrics.clone()))?;
+        registry.register(Box::new(metric_samples.clone()))?;
+        registry.register(Box::new(metric_ci_lower.clone()))?;
+        registry.register(Box::new(metric_ci_upper.clone()))?;
+        registry.register(Box::new(process_tx_syscalls.clone()))?;
+        registry.register(Box::new(process_rx_syscalls.clone()))?;
         registry.register(Box::new(net_power_w.clone()))?;
         registry.register(Box::new(user_space_overhead.clone()))?;
+        registry.register(Box::new(softirq_deferrals.clone()))?;
+        registry.register(Box::new(dropped_samples.clone()))?;
+        registry.register(Box::new(breakdowns_available.clone()))?;
         
         Ok(Self {
             registry,
             encoder,
             metrics,
+            cgroup_metrics,
+            interface_metrics,
+            nf_chain_metrics,
             procfs_metrics,
+            metric_samples,
+            metric_ci_lower,
+            metric_ci_upper,
+            process_tx_syscalls,
+            process_rx_syscalls,
             net_power_w,
             user_space_overhead,
+            softirq_deferrals,
+            dropped_samples,
+            breakdowns_available,
             watch_sender
         })
     }
@@ -1,15 +1,72 @@
-use std::time::{Duration, Instant};
+use std::{time::{Duration, Instant}, collections::{HashMap, BTreeMap}, ops::Range, path::PathBuf, rc::Rc, cell::RefCell};
 use actix::{Actor, Context, AsyncContext, Addr};
 use anyhow::anyhow;
-use libbpf_rs::MapFlags;
+use libbpf_rs::{Map, MapFlags, MapType, RingBuffer, RingBufferBuilder, libbpf_sys};
 use powercap::{IntelRapl, PowerCap};
 use tokio::sync::mpsc::Sender;
-use crate::{ksyms::{Counts, KSyms}, common::{event_types_EVENT_MAX, self, event_types_EVENT_SOCK_SENDMSG, event_types_EVENT_NET_TX_SOFTIRQ, event_types_EVENT_NET_RX_SOFTIRQ, event_types_EVENT_SOCK_RECVMSG, event_types_EVENT_IO_WORKER}, bpf::ProgSkel};
-use libc::{mmap, PROT_READ, MAP_SHARED, sysconf, _SC_CLK_TCK};
-use super::{metrics_collector::MetricsCollector, MetricUpdate, SubmitUpdate};
+use crate::{ksyms::{KSyms, IpCache, BpfProgKind}, categories::{Action, Breakdown, Counts, UNCLASSIFIED_MARK}, cgroups::CgroupNames, irqs, common::{event_types_EVENT_MAX, self, event_types_EVENT_SOCK_SENDMSG, event_types_EVENT_NET_TX_SOFTIRQ, event_types_EVENT_NET_RX_SOFTIRQ, event_types_EVENT_SOCK_RECVMSG, event_types_EVENT_IO_WORKER, event_types_EVENT_HW_IRQ, event_types_EVENT_RX_BUSY_POLL, event_types_EVENT_RX_THREADED_NAPI, event_types_EVENT_SOCK_SENDMSG_TCP, event_types_EVENT_SOCK_SENDMSG_UDP, event_types_EVENT_SOCK_SENDMSG_UNIX, event_types_EVENT_SOCK_SENDMSG_OTHER, event_types_EVENT_SOCK_RECVMSG_TCP, event_types_EVENT_SOCK_RECVMSG_UDP, event_types_EVENT_SOCK_RECVMSG_UNIX, event_types_EVENT_SOCK_RECVMSG_OTHER, event_types_EVENT_NET_RX_SOFTIRQ_IRQ_TAIL, event_types_EVENT_NET_RX_SOFTIRQ_PROCESS, event_types_EVENT_NET_RX_SOFTIRQ_KSOFTIRQD, event_types_EVENT_NET_TX_SOFTIRQ_IRQ_TAIL, event_types_EVENT_NET_TX_SOFTIRQ_PROCESS, event_types_EVENT_NET_TX_SOFTIRQ_KSOFTIRQD, KSYM_RANGES_MAX, KSYM_ACTIONS_MAX, BREAKDOWNS_MAX, MARKS_MAX, NF_HOOK_STATES_MAX, STACK_TRACE_ENTRY_LEN, STACK_TRACE_DEV_NAME_OFF, STACK_TRACE_NF_HOOK_OFF, STACK_TRACE_NF_CHAIN_OFF, STACK_TRACE_FRAMES_OFF}, bpf::ProgSkel};
+use libc::{mmap, munmap, close, PROT_READ, PROT_WRITE, MAP_SHARED, MAP_FAILED, sysconf, _SC_CLK_TCK};
+use metrics_common::{ProcessMetric, SampleStats};
+use super::{metrics_collector::MetricsCollector, MetricUpdate, SubmitUpdate, TopProcessesUpdate};
 #[cfg(feature = "save-traces")]
 use std::fs::File;
 
+/// Events accounted per cgroup by the BPF programs, with their metric names
+const CGROUP_EVENTS: [(u32, &str); 4] = [
+    (event_types_EVENT_SOCK_SENDMSG, "TX syscalls"),
+    (event_types_EVENT_SOCK_RECVMSG, "RX syscalls"),
+    (event_types_EVENT_IO_WORKER,    "IO workers"),
+    (event_types_EVENT_RX_BUSY_POLL, "RX busy poll")
+];
+
+/// Number of consecutive updates with dropped samples after which
+/// the stack traces buffer is grown, if enabled
+const STACK_TRACES_GROW_AFTER: u32 = 3;
+
+/// Maximum size of the stack traces buffer when grown,
+/// as a multiple of its initial size
+const STACK_TRACES_MAX_GROWTH: u32 = 16;
+
+/// Period of the reports of the top unclassified symbols, if enabled
+const UNCLASSIFIED_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Options of the `TraceAnalyzer`, as set from the command line
+pub struct TraceAnalyzerConfig {
+    /// User-space invocation period in ms
+    pub run_interval_ms: u64,
+
+    /// Initial number of entries in each slot of the stack traces buffer of each cpu
+    pub stack_traces_slot_entries: u32,
+
+    /// Whether to grow the stack traces buffer when samples are dropped
+    pub grow_stack_traces: bool,
+
+    /// Whether the stack traces are sent through the ring buffer
+    pub use_ringbuf: bool,
+
+    /// Whether the stack traces are classified in-kernel
+    pub classify_in_kernel: bool,
+
+    /// Breakdowns of the stack traces, as defined in the categories file
+    pub breakdowns: Vec<Breakdown>,
+
+    /// Symbol table to read in place of /proc/kallsyms, if any, with the
+    /// runtime address of its KASLR anchor
+    pub kallsyms: Option<(PathBuf, u64)>,
+
+    /// Number of symbols at the top of the unclassified traces to report, if debugging them
+    pub debug_unclassified: Option<usize>,
+
+    /// Number of threads the stack traces are analyzed by
+    pub trace_workers: usize,
+
+    /// Maximum number of netfilter chains reported individually, 0 if not broken down
+    pub nf_chains_max: usize,
+
+    /// Number of processes to report in each `TopProcessesUpdate`
+    pub top_processes: usize
+}
+
 /// Actor responsible for interacting with BPF via shared maps,
 /// retrieve stack traces from the ring buffer, and analyze them
 /// to provide user-facing performance metrics.
@@ -20,17 +77,83 @@ pub struct TraceAnalyzer {
     /// libbpf's skeleton
     skel: ProgSkel<'static>,
 
-    /// Pointer to the mmaped stack traces array map
+    /// Current stack traces buffer, installed in the `stack_traces` eBPF map
+    stack_traces_buf: Map,
+
+    /// Pointer to the mmaped stack traces buffer
     stack_traces_ptr: *const u64,
 
-    /// Half size of the `stack_traces` eBPF map in number of entries
-    stack_traces_slot_size: u32,
+    /// Pointer to the mmaped array map of the number of traces in each slot of each cpu
+    stack_traces_count_ptr: *mut u64,
+
+    /// Pointer to the mmaped array map of the number of dropped samples in each slot of each cpu
+    stack_traces_dropped_ptr: *mut u64,
+
+    /// Number of entries in each slot of the stack traces buffer
+    stack_traces_slot_entries: u32,
+
+    /// Initial number of entries in each slot of the stack traces buffer
+    stack_traces_initial_slot_entries: u32,
 
-    /// Vec of one Counts for each CPU
-    counts: Vec<Counts>,
+    /// Whether to grow the stack traces buffer when samples are dropped
+    grow_stack_traces: bool,
+
+    /// Number of consecutive updates with dropped samples
+    lossy_updates: u32,
+
+    /// Ring buffer the stack traces are received from, if used
+    /// in place of the stack traces buffer
+    ringbuf: Option<RingBuffer<'static>>,
+
+    /// Records received from `ringbuf` since the last update,
+    /// each padded to `STACK_TRACE_ENTRY_LEN` u64s
+    ringbuf_records: Rc<RefCell<Vec<u64>>>,
+
+    /// Whether the stack traces are classified in-kernel rather
+    /// than retrieved from the stack traces buffer
+    classify_in_kernel: bool,
+
+    /// Number of possible CPUs
+    num_possible_cpus: usize,
+
+    /// Counts of the traces of the current update, merged from all the workers
+    counts: TraceCounts,
+
+    /// Workers the traces are split among, each on its own thread if more than one
+    workers: Vec<TraceWorker>,
 
     /// Kernel symbols for processing the traces
     ksyms: KSyms,
+
+    /// Number of samples at the top of the unclassified traces
+    /// for each symbol, if debugging them
+    unclassified_syms: Option<HashMap<String, u64>>,
+
+    /// Number of symbols in each report of the top unclassified symbols
+    top_unclassified: usize,
+
+    /// Timestamp of the previous report of the top unclassified symbols
+    prev_unclassified_report_ts: Instant,
+
+    /// Number of processes to report in each `TopProcessesUpdate`
+    top_processes: usize,
+
+    /// Maximum number of netfilter chains reported individually, 0 if not broken down
+    nf_chains_max: usize,
+
+    /// Netfilter chains reported individually, with their raw names as found in the traces
+    /// and their table and chain names, in the order they were first sampled
+    nf_chains: Vec<([u64; 4], String, String)>,
+
+    /// XDP and TC programs loaded since the start, in the order they were first seen.
+    /// All of them are reported on each update, so that idle or unloaded programs drop to 0
+    bpf_progs: Vec<(BpfProgKind, String)>,
+
+    /// IRQs of the network devices accounted by the BPF programs, with their names
+    net_irqs: BTreeMap<u32, String>,
+
+    /// Resolver for the ids of the cgroups found in the `per_cgroup` map
+    cgroup_names: CgroupNames,
     
     /// Link to the open powercap interface for power queries
     rapl: Option<IntelRapl>,
@@ -57,6 +180,30 @@ pub struct TraceAnalyzer {
     /// for each cpu for each event
     prev_total_times: Vec<Vec<u64>>,
 
+    /// Total times up to the previous update cycle,
+    /// for each cgroup for each cpu for each event
+    prev_cgroup_total_times: HashMap<u64, Vec<Vec<u64>>>,
+
+    /// Total times up to the previous update cycle,
+    /// for each network IRQ for each cpu
+    prev_irq_total_times: HashMap<u32, Vec<u64>>,
+
+    /// Total TX and RX syscall times up to the previous
+    /// update cycle, for each process
+    prev_process_total_times: HashMap<u32, [u64; 2]>,
+
+    /// Counters of the stack traces classified in-kernel up to the
+    /// previous update cycle, for each event for each cpu
+    prev_category_counts: HashMap<u32, Vec<Vec<u64>>>,
+
+    /// Total number of softirq deferrals, over all cpus,
+    /// up to the previous update cycle
+    prev_softirq_deferrals: u64,
+
+    /// Total number of stack traces that could not be sent through
+    /// the ring buffer, over all cpus, up to the previous update cycle
+    prev_ringbuf_failures: u64,
+
     /// Total energy, as reported by RAPL, up to the
     /// previous update cycle
     prev_total_energy: u64,
@@ -72,21 +219,83 @@ impl TraceAnalyzer {
     /// to be able to acquire it as an owned `libbpf_rs::Map` and
     /// avoid the reference to the lifetime of the main skel.
     pub fn new(
-        run_interval_ms: u64,
-        skel: ProgSkel<'static>,
+        config: TraceAnalyzerConfig,
+        mut skel: ProgSkel<'static>,
         num_possible_cpus: usize,
-        stack_traces_max_entries: u32,
         metrics_collector_addr: Addr<MetricsCollector>,
         error_catcher_sender: Sender<anyhow::Error>
     ) -> anyhow::Result<Self> {
-        let stack_traces_ptr = unsafe { mmap(
-            std::ptr::null_mut(),
-            std::mem::size_of::<u64>() * 128 * stack_traces_max_entries as usize,
-            PROT_READ,
-            MAP_SHARED,
-            skel.maps().stack_traces().fd(),
-            0
-        ) } as *const u64;
+        let TraceAnalyzerConfig {
+            run_interval_ms,
+            stack_traces_slot_entries,
+            grow_stack_traces,
+            use_ringbuf,
+            classify_in_kernel,
+            breakdowns,
+            kallsyms,
+            debug_unclassified,
+            trace_workers,
+            nf_chains_max,
+            top_processes
+        } = config;
+        let (stack_traces_buf, stack_traces_ptr) = create_stack_traces_buf(&skel, stack_traces_slot_entries, num_possible_cpus)?;
+        skel.bss().stack_traces_slot_entries = stack_traces_slot_entries;
+        let stack_traces_count_ptr = mmap_slot_counters(skel.maps().stack_traces_count(), num_possible_cpus)?;
+        let stack_traces_dropped_ptr = mmap_slot_counters(skel.maps().stack_traces_dropped(), num_possible_cpus)?;
+
+        // Copy the records out of the ring buffer, to be processed with the rest of the update
+        let ringbuf_records = Rc::new(RefCell::new(vec![]));
+        let ringbuf = if use_ringbuf {
+            let records = Rc::clone(&ringbuf_records);
+            let mut builder = RingBufferBuilder::new();
+            builder.add(skel.maps().stack_traces_ringbuf(), move |data: &[u8]| {
+                let mut records = records.borrow_mut();
+                let start = records.len();
+                records.resize(start + STACK_TRACE_ENTRY_LEN as usize, 0);
+
+                for (dst, src) in records[start..].iter_mut().zip(data.chunks_exact(std::mem::size_of::<u64>())) {
+                    *dst = u64::from_ne_bytes(src.try_into().unwrap());
+                }
+
+                0
+            })?;
+
+            Some(builder.build()?)
+        } else {
+            None
+        };
+
+        // Push the breakdowns to the BPF programs for the in-kernel classification
+        let mut ksyms = KSyms::load(breakdowns, kallsyms)?;
+        if let Some(reason) = ksyms.unavailable() {
+            println!("Stack trace breakdowns unavailable: {reason}");
+        }
+        if debug_unclassified.is_some() {
+            ksyms.load_all_syms()?;
+        }
+        if classify_in_kernel {
+            push_breakdowns(&mut skel, &ksyms)?;
+        }
+
+        let workers = (0..trace_workers.max(1))
+            .map(|_| TraceWorker {
+                counts: TraceCounts::new(&ksyms, num_possible_cpus),
+                unclassified_syms: debug_unclassified.map(|_| HashMap::new()),
+                ip_cache: IpCache::default(),
+                #[cfg(feature = "save-traces")]
+                traces_output_buf: vec![]
+            })
+            .collect();
+
+        // Register the IRQs of the network devices for accounting
+        let net_irqs = irqs::net_irqs();
+        for irq in net_irqs.keys() {
+            skel.maps().net_irqs().update_percpu(
+                &irq.to_ne_bytes(),
+                &vec![vec![0u8; std::mem::size_of::<u64>()]; num_possible_cpus],
+                MapFlags::ANY
+            )?;
+        }
 
         let rapl = PowerCap::try_default()
             .map(|rapl| rapl.intel_rapl)
@@ -103,10 +312,30 @@ impl TraceAnalyzer {
         Ok(Self {
             run_interval_ms,
             skel,
+            stack_traces_buf,
             stack_traces_ptr,
-            stack_traces_slot_size: stack_traces_max_entries / 2,
-            counts: vec![Counts::default(); num_possible_cpus],
-            ksyms: KSyms::load()?,
+            stack_traces_count_ptr,
+            stack_traces_dropped_ptr,
+            stack_traces_slot_entries,
+            stack_traces_initial_slot_entries: stack_traces_slot_entries,
+            grow_stack_traces,
+            lossy_updates: 0,
+            ringbuf,
+            ringbuf_records,
+            classify_in_kernel,
+            num_possible_cpus,
+            counts: TraceCounts::new(&ksyms, num_possible_cpus),
+            workers,
+            ksyms,
+            unclassified_syms: debug_unclassified.map(|_| HashMap::new()),
+            top_unclassified: debug_unclassified.unwrap_or_default(),
+            prev_unclassified_report_ts: Instant::now(),
+            top_processes,
+            nf_chains_max,
+            nf_chains: vec![],
+            bpf_progs: vec![],
+            net_irqs,
+            cgroup_names: CgroupNames::default(),
             ticks_per_second,
             procfs_metrics_old: vec![0; 10], // TODO: make this agnostic to the actual number of metrics in procfs
             rapl,
@@ -114,6 +343,12 @@ impl TraceAnalyzer {
             error_catcher_sender,
             prev_update_ts: Instant::now(),
             prev_total_times: vec![vec![0;  event_types_EVENT_MAX as _]; num_possible_cpus],
+            prev_cgroup_total_times: HashMap::new(),
+            prev_process_total_times: HashMap::new(),
+            prev_irq_total_times: HashMap::new(),
+            prev_category_counts: HashMap::new(),
+            prev_softirq_deferrals: 0,
+            prev_ringbuf_failures: 0,
             prev_total_energy: 0,
             #[cfg(feature = "save-traces")]
             traces_output_buf: vec![]
@@ -143,51 +378,93 @@ impl TraceAnalyzer {
         });
         
         // Reset counts to zero
-        for counts in &mut self.counts {
-            *counts = Counts::default();
+        self.counts.clear();
+
+        // Pick up any XDP and TC program loaded since the last update
+        self.ksyms.refresh_bpf_progs()?;
+
+        // Pick up any kernel module loaded or unloaded since the last update
+        if self.ksyms.refresh_modules()? && self.classify_in_kernel {
+            push_breakdowns(&mut self.skel, &self.ksyms)?;
         }
 
-        // Drain the stack traces array
-        {
-            // Swap buffer slots and get the number of stack traces in the previously active slot
-            let slot_off = self.skel.bss().stack_traces_slot_off as usize;
-            let num_traces_ref;
-            (self.skel.bss().stack_traces_slot_off, num_traces_ref) = if slot_off > 0 {
-                (0                          , &mut self.skel.bss().stack_traces_count_slot_1)
-            } else {
-                (self.stack_traces_slot_size, &mut self.skel.bss().stack_traces_count_slot_0)
-            };
+        // Drain the stack traces array, unless the traces are classified in-kernel or sent through the ring buffer
+        let mut dropped_samples = 0;
+        if self.classify_in_kernel {
+            self.read_category_counts()?;
+        } else if self.ringbuf.is_some() {
+            self.drain_ringbuf()?;
+        } else {
+            // Swap buffer slots
+            let slot = self.skel.bss().stack_traces_slot as usize;
+            self.skel.bss().stack_traces_slot = if slot > 0 { 0 } else { 1 };
+            let slot_entries = self.stack_traces_slot_entries as usize;
+
+            // Make sure to read the counts *after* swapping the slots
+            let num_traces = (0..self.num_possible_cpus)
+                .map(|cpuid| unsafe {
+                    (self.stack_traces_count_ptr.add(cpuid*2 + slot).read_volatile() as usize).min(slot_entries)
+                })
+                .collect::<Vec<_>>();
 
-            // Make sure to read the count *after* swapping the slots
-            let num_traces = *num_traces_ref;
+            // Count symbols, with each worker taking a range of CPUs
+            let ksyms = &self.ksyms;
+            let traces_ptr = TracesPtr(self.stack_traces_ptr);
+            run_workers(&mut self.workers, self.num_possible_cpus, |worker, cpus| unsafe {
+                for cpuid in cpus {
+                    let slot_ptr = traces_ptr.get().add((cpuid*2 + slot) * slot_entries * STACK_TRACE_ENTRY_LEN as usize);
+
+                    for trace_ptr in (0..num_traces[cpuid]).map(|trace_idx| slot_ptr.add(trace_idx * STACK_TRACE_ENTRY_LEN as usize)) {
+                        worker.process_trace(ksyms, cpuid, trace_ptr);
+                    }
+                }
+            });
 
-            // Count symbols
             unsafe {
-                for trace_ptr in (0..num_traces as usize).map(|trace_idx| self.stack_traces_ptr.add((slot_off + trace_idx) * 128 /* size of a single trace */)) {
-                    // Get the cpuid
-                    let (trace_size, cpuid) = {
-                        let v = trace_ptr.read_volatile();
-
-                        // Note that the trace size is encoded in bytes in the map, but we care about number of u64s
-                        (v >> 35, v & 0xFFFFFFFF)
-                    };
-
-                    self.counts[cpuid as usize].acc_trace(
-                        &self.ksyms,
-                        trace_ptr.add(1),
-                        trace_size as _,
-                        #[cfg(feature = "save-traces")]
-                        &mut self.traces_output_buf
-                    );
+                for cpuid in 0..self.num_possible_cpus {
+                    // Reset the stack traces count for this slot
+                    self.stack_traces_count_ptr.add(cpuid*2 + slot).write_volatile(0);
+
+                    // Collect the samples that did not fit in this slot
+                    let dropped_ptr = self.stack_traces_dropped_ptr.add(cpuid*2 + slot);
+                    dropped_samples += dropped_ptr.read_volatile();
+                    dropped_ptr.write_volatile(0);
                 }
             }
+        }
+        self.merge_workers();
+
+        if now.duration_since(self.prev_unclassified_report_ts) >= UNCLASSIFIED_REPORT_PERIOD {
+            self.report_unclassified();
+            self.prev_unclassified_report_ts = now;
+        }
+
+        // Grow the buffer if the samples keep not fitting in it
+        self.lossy_updates = if dropped_samples > 0 { self.lossy_updates + 1 } else { 0 };
+        if
+            self.grow_stack_traces &&
+            self.lossy_updates >= STACK_TRACES_GROW_AFTER &&
+            self.stack_traces_slot_entries < self.stack_traces_initial_slot_entries * STACK_TRACES_MAX_GROWTH
+        {
+            self.grow_stack_traces_buf()?;
+            self.lossy_updates = 0;
+        }
+
+        let nf_chain_counts = self.count_nf_chains();
 
-            // Reset the stack traces index for this slot
-            *num_traces_ref = 0;
+        for prog in self.ksyms.bpf_progs() {
+            if !self.bpf_progs.iter().any(|(kind, name)| *kind == prog.kind && *name == prog.name) {
+                self.bpf_progs.push((prog.kind, prog.name.clone()));
+            }
         }
 
         // Get a reference to the counts
-        let counts = &self.counts;
+        let counts = &self.counts.per_event;
+        let ksyms = &self.ksyms;
+        let bpf_prog_counts = &self.counts.bpf_progs;
+        let bpf_progs = &self.bpf_progs;
+        let dev_counts = &self.counts.devs;
+        let nf_chains = &self.nf_chains;
 
         // Lookup in the per-cpu map
         let stats = self.skel.maps().per_cpu()
@@ -217,115 +494,306 @@ impl TraceAnalyzer {
                             event_types_EVENT_SOCK_RECVMSG   => "RX syscalls",
                             event_types_EVENT_NET_TX_SOFTIRQ => "TX softirq",
                             event_types_EVENT_IO_WORKER      => "IO workers",
-                            event_types_EVENT_NET_RX_SOFTIRQ => {
-                                // Update sub-events
-                                let denominator = counts[cpuid].net_rx_action.max(1) as f64;
-                                
-                                // Driver poll
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Driver poll",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * (counts[cpuid].__napi_poll - counts[cpuid].netif_receive_skb) as f64 / denominator
-                                });
-
-                                // GRO overhead
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/GRO overhead",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].napi_gro_receive_overhead as f64 / denominator
-                                });
+                            event_types_EVENT_HW_IRQ         => "HW IRQ",
+                            event_types_EVENT_RX_BUSY_POLL   => "RX busy poll",
+                            event_types_EVENT_RX_THREADED_NAPI => "RX threaded NAPI",
+                            event_types_EVENT_SOCK_SENDMSG_TCP   => "TX syscalls/TCP",
+                            event_types_EVENT_SOCK_SENDMSG_UDP   => "TX syscalls/UDP",
+                            event_types_EVENT_SOCK_SENDMSG_UNIX  => "TX syscalls/UNIX",
+                            event_types_EVENT_SOCK_SENDMSG_OTHER => "TX syscalls/Other",
+                            event_types_EVENT_SOCK_RECVMSG_TCP   => "RX syscalls/TCP",
+                            event_types_EVENT_SOCK_RECVMSG_UDP   => "RX syscalls/UDP",
+                            event_types_EVENT_SOCK_RECVMSG_UNIX  => "RX syscalls/UNIX",
+                            event_types_EVENT_SOCK_RECVMSG_OTHER => "RX syscalls/Other",
+                            event_types_EVENT_NET_RX_SOFTIRQ_IRQ_TAIL  => "RX softirq/Context/IRQ tail",
+                            event_types_EVENT_NET_RX_SOFTIRQ_PROCESS   => "RX softirq/Context/Process",
+                            event_types_EVENT_NET_RX_SOFTIRQ_KSOFTIRQD => "RX softirq/Context/ksoftirqd",
+                            event_types_EVENT_NET_TX_SOFTIRQ_IRQ_TAIL  => "TX softirq/Context/IRQ tail",
+                            event_types_EVENT_NET_TX_SOFTIRQ_PROCESS   => "TX softirq/Context/Process",
+                            event_types_EVENT_NET_TX_SOFTIRQ_KSOFTIRQD => "TX softirq/Context/ksoftirqd",
+                            event_types_EVENT_NET_RX_SOFTIRQ => "RX softirq",
+                            _ => unreachable!()
+                        };
 
-                                // XDP generic
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/XDP generic",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].do_xdp_generic as f64 / denominator
-                                });
+                        // Update sub-events, if the traces of the event are broken down
+                        if let (Some((breakdown_idx, root)), Some(counts)) = (ksyms.breakdown_of(event_idx as u32), counts.get(&(event_idx as u32))) {
+                            let breakdown = &ksyms.breakdowns()[breakdown_idx];
+                            let counts = &counts[cpuid];
+                            let traces = counts.get(root);
 
-                                // TC classify
+                            for (name, sub_cpu_frac, samples) in breakdown.breakdown(metric_name, counts, traces, cpu_frac) {
                                 self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/TC classify",
+                                    name: name.into(),
                                     cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].tcf_classify as f64 / denominator
+                                    cgroup: None,
+                                    interface: None,
+                                    nf_chain: None,
+                                    cpu_frac: sub_cpu_frac,
+                                    samples: Some(samples)
                                 });
+                            }
 
-                                // NF ingress
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/NF ingress",
+                            // Per-chain netfilter sub-events, with the chains not reported individually as the "Other" table
+                            if self.nf_chains_max > 0 {
+                                let nf_chain_counts = nf_chain_counts
+                                    .get(&(event_idx as u32))
+                                    .map(|counts| counts[cpuid].as_slice())
+                                    .unwrap_or_default();
+                                let nf_chain_update = |name: String, nf_chain, count: u64| MetricUpdate {
+                                    name: name.into(),
                                     cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].nf_netdev_ingress as f64 / denominator
-                                });
+                                    cgroup: None,
+                                    interface: None,
+                                    nf_chain,
+                                    cpu_frac: cpu_frac * count as f64 / traces.max(1) as f64,
+                                    samples: Some(SampleStats::new(count, traces as _, cpu_frac))
+                                };
 
-                                // Conntrack
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/NF conntrack",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].nf_conntrack_in as f64 / denominator
-                                });
+                                let nf_traces = nf_chain_counts.iter().map(|&count| count as u64).sum();
+                                self.metrics_collector_addr.do_send(nf_chain_update(format!("{metric_name}/Netfilter"), None, nf_traces));
 
-                                // Bridging
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Bridging",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * (counts[cpuid].br_handle_frame - counts[cpuid].netif_receive_skb_sub_br) as f64 / denominator
-                                });
+                                for (idx, (_, table, chain)) in nf_chains.iter().enumerate() {
+                                    self.metrics_collector_addr.do_send(nf_chain_update(
+                                        format!("{metric_name}/Netfilter"),
+                                        Some((table.clone(), chain.clone())),
+                                        nf_chain_counts.get(idx).copied().unwrap_or_default() as _
+                                    ));
+                                }
+                                if nf_chains.len() >= self.nf_chains_max {
+                                    self.metrics_collector_addr.do_send(nf_chain_update(
+                                        format!("{metric_name}/Netfilter"),
+                                        Some(("Other".to_string(), String::new())),
+                                        nf_chain_counts.get(self.nf_chains_max).copied().unwrap_or_default() as _
+                                    ));
+                                }
+                            }
 
-                                // NF prerouting
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/NF prerouting/v4",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].nf_prerouting_v4 as f64 / denominator
-                                });
+                            if event_idx as u32 == event_types_EVENT_NET_RX_SOFTIRQ {
+                                // Per-program sub-events
+                                for (kind, prog_name) in bpf_progs {
+                                    let count = bpf_prog_counts[cpuid].get(&(*kind, prog_name.clone())).copied().unwrap_or_default();
+                                    let hook = match kind {
+                                        BpfProgKind::Xdp => "XDP",
+                                        BpfProgKind::Tc  => "TC classify"
+                                    };
 
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/NF prerouting/v6",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].nf_prerouting_v6 as f64 / denominator
-                                });
+                                    self.metrics_collector_addr.do_send(MetricUpdate {
+                                        name: format!("{metric_name}/{hook}/{prog_name}").into(),
+                                        cpuid,
+                                        cgroup: None,
+                                        interface: None,
+                                        nf_chain: None,
+                                        cpu_frac: cpu_frac * count as f64 / traces.max(1) as f64,
+                                        samples: Some(SampleStats::new(count as _, traces as _, cpu_frac))
+                                    });
+                                }
 
-                                // Forwarding
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Forwarding/v4",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].ip_forward as f64 / denominator
-                                });
-
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Forwarding/v6",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].ip6_forward as f64 / denominator
-                                });
-
-                                // Local deliver
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Local delivery/v4",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].ip_local_deliver as f64 / denominator
-                                });
+                                // Per-device sub-events
+                                if let Some(device) = breakdown.device() {
+                                    for (dev_name, dev_counts) in &dev_counts[cpuid] {
+                                        let interface = name_from_trace(dev_name);
+                                        let dev_traces = dev_counts.get(device);
 
-                                self.metrics_collector_addr.do_send(MetricUpdate {
-                                    name: "RX softirq/Local delivery/v6",
-                                    cpuid,
-                                    cpu_frac: cpu_frac * counts[cpuid].ip6_input as f64 / denominator
-                                });
+                                        self.metrics_collector_addr.do_send(MetricUpdate {
+                                            name: metric_name.into(),
+                                            cpuid,
+                                            cgroup: None,
+                                            interface: Some(interface.clone()),
+                                            nf_chain: None,
+                                            cpu_frac: cpu_frac * dev_traces as f64 / traces.max(1) as f64,
+                                            samples: Some(SampleStats::new(dev_traces as _, traces as _, cpu_frac))
+                                        });
 
-                                "RX softirq"
-                            },
-                            _ => unreachable!()
-                        };
+                                        for (name, sub_cpu_frac, samples) in breakdown.breakdown(metric_name, dev_counts, traces, cpu_frac) {
+                                            self.metrics_collector_addr.do_send(MetricUpdate {
+                                                name: name.into(),
+                                                cpuid,
+                                                cgroup: None,
+                                                interface: Some(interface.clone()),
+                                                nf_chain: None,
+                                                cpu_frac: sub_cpu_frac,
+                                                samples: Some(samples)
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                        }
 
                         self.metrics_collector_addr.do_send(MetricUpdate {
-                            name: metric_name,
+                            name: metric_name.into(),
                             cpuid,
-                            cpu_frac
+                            cgroup: None,
+                            interface: None,
+                            nf_chain: None,
+                            cpu_frac,
+                            samples: None
                         });
 
-                        cpu_frac
+                        // Sub-events are already accounted in their parent event
+                        if metric_name.contains('/') { 0.0 } else { cpu_frac }
                     })
                     .sum::<f64>()
             })
             .sum::<f64>() / (self.prev_total_times.len() as f64);
 
+        // Softirq deferrals to ksoftirqd
+        let softirq_deferrals = stats
+            .iter()
+            .map(|cpu_stats| unsafe {
+                (cpu_stats.as_ptr() as *const common::per_cpu_data).read_unaligned()
+            }.softirq_deferrals)
+            .sum::<u64>();
+        let delta_softirq_deferrals = softirq_deferrals - self.prev_softirq_deferrals;
+        self.prev_softirq_deferrals = softirq_deferrals;
+
+        // Ring buffer reservation failures are dropped samples too
+        let ringbuf_failures = stats
+            .iter()
+            .map(|cpu_stats| unsafe {
+                (cpu_stats.as_ptr() as *const common::per_cpu_data).read_unaligned()
+            }.ringbuf_failures)
+            .sum::<u64>();
+        dropped_samples += ringbuf_failures - self.prev_ringbuf_failures;
+        self.prev_ringbuf_failures = ringbuf_failures;
+
+        // Per-IRQ sub-events of HW IRQ
+        for (irq, irq_name) in &self.net_irqs {
+            let Some(irq_stats) = self.skel.maps().net_irqs().lookup_percpu(&irq.to_ne_bytes(), MapFlags::empty())? else {
+                continue;
+            };
+
+            let prev_irq_total_times = self.prev_irq_total_times
+                .entry(*irq)
+                .or_insert_with(|| vec![0; irq_stats.len()]);
+
+            for (cpuid, (cpu_stats, prev_total_time)) in irq_stats.iter().zip(prev_irq_total_times.iter_mut()).enumerate() {
+                let total_time = u64::from_ne_bytes(cpu_stats[..8].try_into().unwrap());
+                let delta_cpu_time = total_time - *prev_total_time;
+                *prev_total_time = total_time;
+
+                self.metrics_collector_addr.do_send(MetricUpdate {
+                    name: format!("HW IRQ/{irq_name}").into(),
+                    cpuid,
+                    cgroup: None,
+                    interface: None,
+                    nf_chain: None,
+                    cpu_frac: (delta_cpu_time as f64) / (delta_time.as_nanos() as f64),
+                    samples: None
+                });
+            }
+        }
+
+        // Per-cgroup metrics, unless the cgroups cannot be resolved
+        if self.skel.rodata().account_cgroups {
+            let maps = self.skel.maps();
+            let per_cgroup = maps.per_cgroup();
+            let cgroup_ids = per_cgroup
+                .keys()
+                .map(|key| u64::from_ne_bytes(key[..8].try_into().unwrap()))
+                .collect::<Vec<_>>();
+
+            self.cgroup_names.refresh(cgroup_ids.iter());
+
+            for cgroup_id in cgroup_ids {
+                let key = cgroup_id.to_ne_bytes();
+                
+                let Some(cgroup_name) = self.cgroup_names.get(cgroup_id) else {
+                    // The cgroup was removed, stop tracking it
+                    per_cgroup.delete(&key)?;
+                    self.prev_cgroup_total_times.remove(&cgroup_id);
+                    continue;
+                };
+
+                let Some(stats) = per_cgroup.lookup_percpu(&key, MapFlags::empty())? else {
+                    continue;
+                };
+
+                let prev_cgroup_total_times = self.prev_cgroup_total_times
+                    .entry(cgroup_id)
+                    .or_insert_with(|| vec![vec![0; event_types_EVENT_MAX as _]; stats.len()]);
+
+                for (cpuid, (cpu_stats, prev_total_cpu_times)) in stats.iter().zip(prev_cgroup_total_times.iter_mut()).enumerate() {
+                    let per_event_total_time = unsafe {
+                        (cpu_stats.as_ptr() as *const common::per_cgroup_data).read_unaligned()
+                    }.per_event_total_time;
+
+                    for (event_idx, metric_name) in CGROUP_EVENTS {
+                        let total_time = per_event_total_time[event_idx as usize];
+                        let prev_total_time = &mut prev_total_cpu_times[event_idx as usize];
+                        let delta_cpu_time = total_time.saturating_sub(*prev_total_time);
+                        *prev_total_time = total_time;
+
+                        self.metrics_collector_addr.do_send(MetricUpdate {
+                            name: metric_name.into(),
+                            cpuid,
+                            cgroup: Some(cgroup_name.to_string()),
+                            interface: None,
+                            nf_chain: None,
+                            cpu_frac: (delta_cpu_time as f64) / (delta_time.as_nanos() as f64),
+                            samples: None
+                        });
+                    }
+                }
+            }
+        }
+
+        // Per-process metrics
+        {
+            let maps = self.skel.maps();
+            let per_process = maps.per_process();
+            let mut processes = vec![];
+            let mut process_total_times = HashMap::with_capacity(self.prev_process_total_times.len());
+
+            for key in per_process.keys() {
+                let Some(value) = per_process.lookup(&key, MapFlags::empty())? else {
+                    // The entry was evicted in the meantime
+                    continue;
+                };
+
+                let pid = u32::from_ne_bytes(key[..4].try_into().unwrap());
+                let process_data = unsafe {
+                    (value.as_ptr() as *const common::per_process_data).read_unaligned()
+                };
+
+                let total_times = [
+                    process_data.per_event_total_time[event_types_EVENT_SOCK_SENDMSG as usize],
+                    process_data.per_event_total_time[event_types_EVENT_SOCK_RECVMSG as usize]
+                ];
+                let prev_total_times = self.prev_process_total_times
+                    .get(&pid)
+                    .copied()
+                    .unwrap_or_default();
+                process_total_times.insert(pid, total_times);
+
+                let [tx_cpu_frac, rx_cpu_frac] = [0, 1].map(|i| {
+                    total_times[i].saturating_sub(prev_total_times[i]) as f64 / delta_time.as_nanos() as f64
+                });
+
+                if tx_cpu_frac + rx_cpu_frac > 0.0 {
+                    let comm = process_data.comm.map(|c| c as u8);
+                    let comm_len = comm.iter().position(|&c| c == 0).unwrap_or(comm.len());
+
+                    processes.push(ProcessMetric {
+                        pid,
+                        comm: String::from_utf8_lossy(&comm[..comm_len]).into_owned(),
+                        tx_cpu_frac,
+                        rx_cpu_frac
+                    });
+                }
+            }
+
+            // Also forget about the processes that have been evicted from the map
+            self.prev_process_total_times = process_total_times;
+
+            processes.sort_unstable_by(|a, b| {
+                (b.tx_cpu_frac + b.rx_cpu_frac).total_cmp(&(a.tx_cpu_frac + a.rx_cpu_frac))
+            });
+            processes.truncate(self.top_processes);
+
+            self.metrics_collector_addr.do_send(TopProcessesUpdate { processes });
+        }
+
         // Collect /proc/stat metrics
         let procfs_metrics = std::fs::read_to_string("/proc/stat")?
             .lines()
@@ -346,11 +814,536 @@ impl TraceAnalyzer {
         self.metrics_collector_addr.do_send(SubmitUpdate {
             net_power_w: delta_energy.map(|e| (e as f64) * total_cpu_frac / (delta_time.as_secs_f64() * 1_000_000.0)),
             user_space_overhead: now.elapsed().as_secs_f64() / delta_time.as_secs_f64(),
+            softirq_deferrals: delta_softirq_deferrals,
+            dropped_samples,
+            breakdowns_unavailable: self.ksyms.unavailable().map(str::to_string),
             procfs_metrics
         });
 
         Ok(())
     }
+
+    /// Merge the counts of the traces accounted by the workers, and reset them
+    fn merge_workers(&mut self) {
+        for worker in &mut self.workers {
+            self.counts.merge(&worker.counts);
+            worker.counts.clear();
+
+            if let (Some(unclassified_syms), Some(worker_unclassified_syms)) = (&mut self.unclassified_syms, &mut worker.unclassified_syms) {
+                for (name, count) in worker_unclassified_syms.drain() {
+                    *unclassified_syms.entry(name).or_default() += count;
+                }
+            }
+
+            #[cfg(feature = "save-traces")]
+            {
+                self.traces_output_buf.append(&mut worker.traces_output_buf);
+            }
+        }
+    }
+
+    /// Counts of the traces in each of the netfilter chains reported individually, for each
+    /// event for each CPU, followed by the count of the traces in any other chain. Chains sampled
+    /// for the first time are reported individually as long as fewer than `nf_chains_max` are.
+    fn count_nf_chains(&mut self) -> HashMap<u32, Vec<Vec<u16>>> {
+        let mut nf_chain_counts = HashMap::new();
+
+        for (cpuid, cpu_nf_chain_counts) in self.counts.nf_chains.iter().enumerate() {
+            for (&(event, raw_names), &count) in cpu_nf_chain_counts {
+                let idx = match self.nf_chains.iter().position(|(n, ..)| *n == raw_names) {
+                    Some(idx) => idx,
+                    None if self.nf_chains.len() < self.nf_chains_max => {
+                        self.nf_chains.push((raw_names, name_from_trace(&raw_names[..2]), name_from_trace(&raw_names[2..])));
+                        self.nf_chains.len() - 1
+                    },
+                    None => self.nf_chains_max
+                };
+
+                nf_chain_counts
+                    .entry(event)
+                    .or_insert_with(|| vec![vec![0; self.nf_chains_max + 1]; self.num_possible_cpus])
+                    [cpuid][idx] += count;
+            }
+        }
+
+        nf_chain_counts
+    }
+
+    /// Print the symbols found most often at the top of the unclassified
+    /// traces since the previous report, and start over
+    fn report_unclassified(&mut self) {
+        let Some(unclassified_syms) = &mut self.unclassified_syms else {
+            return;
+        };
+
+        let total = unclassified_syms.values().sum::<u64>();
+        let mut syms = unclassified_syms.drain().collect::<Vec<_>>();
+        syms.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+
+        println!("Top unclassified symbols over the last {}s ({total} samples):", UNCLASSIFIED_REPORT_PERIOD.as_secs());
+        for (name, count) in syms.iter().take(self.top_unclassified) {
+            println!("{: >8.02}% {name}", *count as f64 * 100.0 / total.max(1) as f64);
+        }
+    }
+
+    /// Account all the stack traces received through the ring buffer since the last update
+    fn drain_ringbuf(&mut self) -> anyhow::Result<()> {
+        if let Some(ringbuf) = &self.ringbuf {
+            ringbuf.consume()?;
+        }
+
+        let mut records = std::mem::take(&mut *self.ringbuf_records.borrow_mut());
+
+        // Records are in no particular order of CPU, so each worker takes a range of them
+        let ksyms = &self.ksyms;
+        let num_possible_cpus = self.num_possible_cpus;
+        let entry_len = STACK_TRACE_ENTRY_LEN as usize;
+        run_workers(&mut self.workers, records.len() / entry_len, |worker, range| {
+            for record in records[range.start * entry_len..range.end * entry_len].chunks_exact(entry_len) {
+                // The cpu id is encoded in bits 8 to 31 of the first u64
+                let cpuid = ((record[0] >> 8) & 0xFF_FFFF) as usize;
+
+                if cpuid < num_possible_cpus {
+                    unsafe { worker.process_trace(ksyms, cpuid, record.as_ptr()) };
+                }
+            }
+        });
+
+        // Keep the allocation for the next update
+        records.clear();
+        *self.ringbuf_records.borrow_mut() = records;
+
+        Ok(())
+    }
+
+    /// Fill the per-cpu counts with the counters of the
+    /// stack traces classified in-kernel since the last update
+    fn read_category_counts(&mut self) -> anyhow::Result<()> {
+        for (&event, counts) in &mut self.counts.per_event {
+            let stats = self.skel.maps().category_counts()
+                .lookup_percpu(&event.to_ne_bytes(), MapFlags::empty())?
+                .ok_or(anyhow!("Unexpected None returned for lookup into the \"category_counts\" map"))?;
+
+            let prev_category_counts = self.prev_category_counts
+                .entry(event)
+                .or_insert_with(|| vec![vec![0; MARKS_MAX as usize + 1]; stats.len()]);
+
+            for (cpu_counts, (cpu_stats, prev_counts)) in counts.iter_mut().zip(stats.iter().zip(prev_category_counts.iter_mut())) {
+                let delta_counts = unsafe {
+                    (cpu_stats.as_ptr() as *const common::category_counts).read_unaligned()
+                }.counts
+                    .iter()
+                    .zip(prev_counts.iter_mut())
+                    .map(|(count, prev_count)| {
+                        let delta_count = count - *prev_count;
+                        *prev_count = *count;
+                        delta_count
+                    })
+                    .collect::<Vec<_>>();
+
+                cpu_counts.set_from_kernel(&delta_counts);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the stack traces buffer with one twice as large.
+    /// The traces in the slots currently in use by the BPF programs are lost.
+    fn grow_stack_traces_buf(&mut self) -> anyhow::Result<()> {
+        let num_possible_cpus = self.num_possible_cpus;
+        let slot_entries = self.stack_traces_slot_entries * 2;
+        let (buf, ptr) = create_stack_traces_buf(&self.skel, slot_entries, num_possible_cpus)?;
+
+        // The new buffer is larger, so it's safe for it to be used with the old slot size for a while
+        self.skel.bss().stack_traces_slot_entries = slot_entries;
+        unsafe {
+            for i in 0..num_possible_cpus*2 {
+                self.stack_traces_count_ptr.add(i).write_volatile(0);
+            }
+
+            munmap(self.stack_traces_ptr as _, stack_traces_buf_size(self.stack_traces_slot_entries, num_possible_cpus));
+            close(self.stack_traces_buf.fd());
+        }
+        
+        println!(
+            "Samples are being dropped, grew the stack traces buffer to {}B",
+            stack_traces_buf_size(slot_entries, num_possible_cpus)
+        );
+        self.stack_traces_buf = buf;
+        self.stack_traces_ptr = ptr;
+        self.stack_traces_slot_entries = slot_entries;
+
+        Ok(())
+    }
+}
+
+/// Counts of the stack traces accounted in an update
+struct TraceCounts {
+    /// Vec of one Counts for each CPU, for each event whose traces are broken down
+    per_event: HashMap<u32, Vec<Counts>>,
+
+    /// Counts of the RX softirq traces sampled in each XDP and TC BPF program,
+    /// keyed by the kind and name of the program, for each CPU
+    bpf_progs: Vec<HashMap<(BpfProgKind, String), u16>>,
+
+    /// Counts of the traces sampled while polling each device, for each CPU.
+    /// Devices are keyed by their raw name as found in the trace.
+    devs: Vec<HashMap<[u64; 2], Counts>>,
+
+    /// Counts of the traces of each event sampled while evaluating each netfilter chain,
+    /// for each CPU. Chains are keyed by the raw names of their table and chain as found in the trace.
+    nf_chains: Vec<HashMap<(u32, [u64; 4]), u16>>
+}
+
+impl TraceCounts {
+    fn new(ksyms: &KSyms, num_possible_cpus: usize) -> Self {
+        Self {
+            per_event: ksyms.breakdowns()
+                .iter()
+                .flat_map(|breakdown| breakdown
+                    .events()
+                    .map(move |(event, _)| (event, vec![breakdown.counts(); num_possible_cpus]))
+                )
+                .collect(),
+            bpf_progs: vec![HashMap::new(); num_possible_cpus],
+            devs: vec![HashMap::new(); num_possible_cpus],
+            nf_chains: vec![HashMap::new(); num_possible_cpus]
+        }
+    }
+
+    /// Reset all counts to zero
+    fn clear(&mut self) {
+        for counts in self.per_event.values_mut().flatten() {
+            counts.clear();
+        }
+        for bpf_prog_counts in &mut self.bpf_progs {
+            bpf_prog_counts.clear();
+        }
+        for dev_counts in &mut self.devs {
+            dev_counts.clear();
+        }
+        for nf_chain_counts in &mut self.nf_chains {
+            nf_chain_counts.clear();
+        }
+    }
+
+    /// Add the counts of `other`
+    fn merge(&mut self, other: &Self) {
+        for (event, counts) in &mut self.per_event {
+            if let Some(other_counts) = other.per_event.get(event) {
+                for (counts, other_counts) in counts.iter_mut().zip(other_counts) {
+                    counts.merge(other_counts);
+                }
+            }
+        }
+        for (bpf_prog_counts, other_bpf_prog_counts) in self.bpf_progs.iter_mut().zip(&other.bpf_progs) {
+            for (prog, &count) in other_bpf_prog_counts {
+                *bpf_prog_counts.entry(prog.clone()).or_default() += count;
+            }
+        }
+        for (dev_counts, other_dev_counts) in self.devs.iter_mut().zip(&other.devs) {
+            for (dev_name, other_counts) in other_dev_counts {
+                match dev_counts.get_mut(dev_name) {
+                    Some(counts) => counts.merge(other_counts),
+                    None => { dev_counts.insert(*dev_name, other_counts.clone()); }
+                }
+            }
+        }
+        for (nf_chain_counts, other_nf_chain_counts) in self.nf_chains.iter_mut().zip(&other.nf_chains) {
+            for (key, &count) in other_nf_chain_counts {
+                *nf_chain_counts.entry(*key).or_default() += count;
+            }
+        }
+    }
+}
+
+/// Accounts its share of the stack traces of each update
+struct TraceWorker {
+    /// Counts of the traces accounted by this worker in the current update
+    counts: TraceCounts,
+
+    /// Number of samples at the top of the unclassified traces
+    /// for each symbol, if debugging them
+    unclassified_syms: Option<HashMap<String, u64>>,
+
+    /// Cache of the symbol lookups of this worker
+    ip_cache: IpCache,
+
+    #[cfg(feature = "save-traces")]
+    traces_output_buf: Vec<u8>
+}
+
+impl TraceWorker {
+    /// Account a single stack trace, as laid out in the stack traces buffer,
+    /// to the counts of `cpuid`
+    #[inline]
+    unsafe fn process_trace(&mut self, ksyms: &KSyms, cpuid: usize, trace_ptr: *const u64) {
+        // Get the event the trace is accounted to
+        let (trace_size, event) = {
+            let v = trace_ptr.read_volatile();
+
+            // Note that the trace size is encoded in bytes in the map, but we care about number of u64s
+            (v >> 35, (v & 0xFF) as u32)
+        };
+
+        // Only the traces of the events being broken down are of interest
+        let Some((breakdown_idx, _)) = ksyms.breakdown_of(event) else {
+            return;
+        };
+        let frames_ptr = trace_ptr.add(STACK_TRACE_FRAMES_OFF as _);
+
+        let mut marks = ksyms.classify(
+            breakdown_idx,
+            &mut self.ip_cache,
+            frames_ptr,
+            trace_size as _,
+            #[cfg(feature = "save-traces")]
+            &mut self.traces_output_buf
+        );
+        let breakdown = &ksyms.breakdowns()[breakdown_idx];
+
+        if event == event_types_EVENT_NET_RX_SOFTIRQ {
+            // Account the trace to the XDP or TC program it was sampled in, if any
+            if let Some(prog) = ksyms.bpf_prog_from_trace(frames_ptr, trace_size as _) {
+                if breakdown.account_bpf_prog(prog, &mut marks) {
+                    *self.counts.bpf_progs[cpuid].entry((prog.kind, prog.name.clone())).or_default() += 1;
+                }
+            }
+        }
+
+        // Account the trace to the netfilter hook it was sampled in, if any
+        let in_nf_hook = breakdown.in_nf_hook(marks);
+        breakdown.account_nf_hook(trace_ptr.add(STACK_TRACE_NF_HOOK_OFF as _).read_volatile() as u8, &mut marks);
+
+        let marks = breakdown.finish(event, marks);
+        if let Some(counts) = self.counts.per_event.get_mut(&event) {
+            counts[cpuid].add(marks);
+        }
+
+        // Also account the trace to the device being polled, if any
+        if event == event_types_EVENT_NET_RX_SOFTIRQ {
+            let dev_name_ptr = trace_ptr.add(STACK_TRACE_DEV_NAME_OFF as _);
+            let dev_name = [dev_name_ptr.read_volatile(), dev_name_ptr.add(1).read_volatile()];
+            if dev_name[0].to_ne_bytes()[0] != 0 {
+                self.counts.devs[cpuid]
+                    .entry(dev_name)
+                    .or_insert_with(|| breakdown.counts())
+                    .add(marks);
+            }
+        }
+
+        // Also account the trace to the netfilter chain being evaluated, if any,
+        // provided that it is in the hook like for `account_nf_hook`
+        if in_nf_hook {
+            let nf_chain_ptr = trace_ptr.add(STACK_TRACE_NF_CHAIN_OFF as _);
+            let nf_chain = [0, 1, 2, 3].map(|idx| nf_chain_ptr.add(idx).read_volatile());
+            if nf_chain[0].to_ne_bytes()[0] != 0 {
+                *self.counts.nf_chains[cpuid].entry((event, nf_chain)).or_default() += 1;
+            }
+        }
+
+        if marks & UNCLASSIFIED_MARK != 0 {
+            self.record_unclassified(ksyms, trace_ptr);
+        }
+    }
+
+    /// Record the innermost symbol of an unclassified trace, if debugging them
+    #[inline]
+    unsafe fn record_unclassified(&mut self, ksyms: &KSyms, trace_ptr: *const u64) {
+        let Some(unclassified_syms) = &mut self.unclassified_syms else {
+            return;
+        };
+
+        let ip = trace_ptr.add(STACK_TRACE_FRAMES_OFF as _).read_volatile();
+        let name = ksyms.symbolize(ip).unwrap_or("[unknown]");
+        if let Some(count) = unclassified_syms.get_mut(name) {
+            *count += 1;
+        } else {
+            unclassified_syms.insert(name.to_string(), 1);
+        }
+    }
+}
+
+/// Pointer into the mmaped stack traces buffer, to be shared with the workers.
+/// The workers only read the disjoint slots of the CPUs they are given.
+#[derive(Clone, Copy)]
+struct TracesPtr(*const u64);
+
+unsafe impl Send for TracesPtr {}
+unsafe impl Sync for TracesPtr {}
+
+impl TracesPtr {
+    /// Accessor, so that closures capture the whole `Send` wrapper rather than the pointer
+    fn get(self) -> *const u64 {
+        self.0
+    }
+}
+
+/// Split `0..len` into a contiguous range for each of the `workers` and run `f` on each,
+/// on a thread each if there is more than one worker.
+/// The threads are spawned anew on each call, i.e. once or twice per update. This costs
+/// tens of microseconds per thread, negligible next to the update period, and lets them
+/// borrow the symbols and the stack traces buffer rather than share them with a pool.
+fn run_workers<F>(workers: &mut [TraceWorker], len: usize, f: F)
+where
+    F: Fn(&mut TraceWorker, Range<usize>) + Sync
+{
+    if let [worker] = workers {
+        f(worker, 0..len);
+        return;
+    }
+
+    let chunk_len = len.div_ceil(workers.len());
+    std::thread::scope(|s| {
+        for (idx, worker) in workers.iter_mut().enumerate() {
+            let range = (idx * chunk_len).min(len)..((idx + 1) * chunk_len).min(len);
+            if !range.is_empty() {
+                let f = &f;
+                s.spawn(move || f(worker, range));
+            }
+        }
+    });
+}
+
+/// Decode a NUL-terminated name, as copied in the raw `words` of a trace
+fn name_from_trace(words: &[u64]) -> String {
+    let name = words.iter().flat_map(|word| word.to_ne_bytes()).collect::<Vec<_>>();
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+/// Size in bytes of a stack traces buffer with `slot_entries` entries in each slot of each cpu
+fn stack_traces_buf_size(slot_entries: u32, num_possible_cpus: usize) -> usize {
+    std::mem::size_of::<u64>() * STACK_TRACE_ENTRY_LEN as usize * slot_entries as usize * 2 * num_possible_cpus
+}
+
+/// Create a stack traces buffer with `slot_entries` entries in each slot of each cpu,
+/// install it in the `stack_traces` map for the BPF programs, and mmap it
+fn create_stack_traces_buf(skel: &ProgSkel, slot_entries: u32, num_possible_cpus: usize) -> anyhow::Result<(Map, *const u64)> {
+    let buf = Map::create(
+        MapType::Array,
+        Some("stack_traces_buf"),
+        std::mem::size_of::<u32>() as _,
+        (std::mem::size_of::<u64>() * STACK_TRACE_ENTRY_LEN as usize) as _,
+        slot_entries * 2 * num_possible_cpus as u32,
+        &libbpf_sys::bpf_map_create_opts {
+            sz: std::mem::size_of::<libbpf_sys::bpf_map_create_opts>() as _,
+            map_flags: libbpf_sys::BPF_F_MMAPABLE | libbpf_sys::BPF_F_INNER_MAP,
+            ..Default::default()
+        }
+    )?;
+
+    let ptr = unsafe { mmap(
+        std::ptr::null_mut(),
+        stack_traces_buf_size(slot_entries, num_possible_cpus),
+        PROT_READ,
+        MAP_SHARED,
+        buf.fd(),
+        0
+    ) };
+    if ptr == MAP_FAILED {
+        unsafe { close(buf.fd()) };
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    skel.maps().stack_traces().update(&0u32.to_ne_bytes(), &buf.fd().to_ne_bytes(), MapFlags::ANY)?;
+
+    Ok((buf, ptr as *const u64))
+}
+
+/// Mmap one of the arrays holding a counter for each of the two
+/// stack trace slots of each cpu, like `stack_traces_count`
+fn mmap_slot_counters(map: &Map, num_possible_cpus: usize) -> anyhow::Result<*mut u64> {
+    let ptr = unsafe { mmap(
+        std::ptr::null_mut(),
+        std::mem::size_of::<u64>() * 2 * num_possible_cpus,
+        PROT_READ | PROT_WRITE,
+        MAP_SHARED,
+        map.fd(),
+        0
+    ) };
+    if ptr == MAP_FAILED {
+        return Err(anyhow!("Failed to mmap the \"{}\" map: {}", map.name(), std::io::Error::last_os_error()));
+    }
+
+    Ok(ptr as *mut u64)
+}
+
+/// Push the symbols of interest of the breakdowns, and the actions to run on them,
+/// to the BPF programs for the in-kernel classification
+fn push_breakdowns(skel: &mut ProgSkel, ksyms: &KSyms) -> anyhow::Result<()> {
+    if ksyms.breakdowns().len() > BREAKDOWNS_MAX as usize {
+        anyhow::bail!("At most {BREAKDOWNS_MAX} breakdowns can be classified in-kernel");
+    }
+
+    // Stop the lookups while the ranges are replaced, when reloading
+    skel.bss().ksym_ranges_len = 0;
+
+    let mut ksym_ranges_len = 0;
+    for (idx, range) in ksyms.kernel_ranges().enumerate() {
+        if idx >= KSYM_RANGES_MAX as usize {
+            println!("Too many kernel symbols of interest, only the first {KSYM_RANGES_MAX} are classified in-kernel");
+            break;
+        }
+
+        let mut value = vec![0u8; std::mem::size_of::<common::ksym_range>()];
+        unsafe { (value.as_mut_ptr() as *mut common::ksym_range).write_unaligned(range) };
+        skel.maps().ksym_ranges().update(&(idx as u32).to_ne_bytes(), &value, MapFlags::ANY)?;
+        ksym_ranges_len = idx as u32 + 1;
+    }
+
+    for (idx, breakdown, actions) in ksyms.kernel_actions() {
+        if idx < KSYM_RANGES_MAX as usize {
+            let key = (breakdown * KSYM_RANGES_MAX as usize + idx) as u32;
+            skel.maps().ksym_actions().update(&key.to_ne_bytes(), &kernel_actions(actions)?, MapFlags::ANY)?;
+        }
+    }
+    skel.bss().ksym_ranges_len = ksym_ranges_len;
+
+    for (idx, breakdown) in ksyms.breakdowns().iter().enumerate() {
+        skel.maps().finally_actions().update(&(idx as u32).to_ne_bytes(), &kernel_actions(breakdown.finally())?, MapFlags::ANY)?;
+
+        for (event, root) in breakdown.events() {
+            let mut event_breakdown = common::event_breakdown {
+                // Only look for the unclassified traces if they are reported
+                root_mask: if breakdown.other().is_some() { 1 << root } else { 0 },
+                metrics_mask: breakdown.metrics_mask(),
+                nf_hook_mask: breakdown.nf_hook_mask(),
+                breakdown: idx as u32,
+                enabled: 1,
+                nf_hook_marks: [0; NF_HOOK_STATES_MAX as usize]
+            };
+            event_breakdown.nf_hook_marks.copy_from_slice(breakdown.nf_hook_marks());
+
+            let mut value = vec![0u8; std::mem::size_of::<common::event_breakdown>()];
+            unsafe { (value.as_mut_ptr() as *mut common::event_breakdown).write_unaligned(event_breakdown) };
+            skel.maps().event_breakdowns().update(&event.to_ne_bytes(), &value, MapFlags::ANY)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lay out `actions` as an element of the `ksym_actions` and `finally_actions` maps
+fn kernel_actions(actions: &[Action]) -> anyhow::Result<Vec<u8>> {
+    if actions.len() > KSYM_ACTIONS_MAX as usize {
+        anyhow::bail!("At most {KSYM_ACTIONS_MAX} actions for each symbol, and once per trace, can be run in-kernel");
+    }
+
+    let mut ksym_actions: common::ksym_actions = unsafe { std::mem::zeroed() };
+    ksym_actions.len = actions.len() as _;
+    for (dst, action) in ksym_actions.actions.iter_mut().zip(actions) {
+        dst.if_mask = action.if_mask;
+        dst.unless_mask = action.unless_mask;
+        dst.mark = action.mark;
+        dst.set = action.set as _;
+    }
+
+    let mut value = vec![0u8; std::mem::size_of::<common::ksym_actions>()];
+    unsafe { (value.as_mut_ptr() as *mut common::ksym_actions).write_unaligned(ksym_actions) };
+
+    Ok(value)
 }
 
 impl Actor for TraceAnalyzer {
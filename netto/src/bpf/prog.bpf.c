@@ -1,6 +1,7 @@
 #include "vmlinux.h"
 #include <bpf/bpf_helpers.h>
 #include <bpf/bpf_tracing.h>
+#include <bpf/bpf_core_read.h>
 #include "prog.bpf.h"
 
 #ifndef likely
@@ -12,6 +13,49 @@
 
 char LICENSE[] SEC("license") = "GPL";
 
+#define AF_UNIX  1
+#define AF_INET  2
+#define AF_INET6 10
+
+/**
+ * The nftables and iptables types are defined in their modules, so they are not in
+ * vmlinux.h. Only the fields read are declared, and relocated against the module BTF.
+ * They are only read by the programs attached to the modules, which are not loaded
+ * without them.
+ */
+struct nft_table___netto {
+    char* name;
+} __attribute__((preserve_access_index));
+
+struct nft_chain___netto {
+    struct nft_table___netto* table;
+    char* name;
+} __attribute__((preserve_access_index));
+
+struct xt_table___netto {
+    char name[32];
+} __attribute__((preserve_access_index));
+
+/**
+ * Names of the built-in iptables chains, indexed by hook
+ */
+const char xt_chain_names[5][NF_CHAIN_NAME_LEN] = { "PREROUTING", "INPUT", "FORWARD", "OUTPUT", "POSTROUTING" };
+
+/**
+ * Per-cpu masks of the raised softirqs, found in `irq_stat.__softirq_pending` in general,
+ * in `pcpu_hot.softirq_pending` on x86 from Linux 6.2 and in `__softirq_pending` on x86
+ * from Linux 6.15. All weak, as only one of them exists on a given kernel.
+ */
+extern irq_cpustat_t irq_stat __ksym __weak;
+
+struct pcpu_hot___netto {
+    u16 softirq_pending;
+} __attribute__((preserve_access_index));
+
+extern struct pcpu_hot___netto pcpu_hot __ksym __weak;
+
+extern u16 __softirq_pending __ksym __weak;
+
 /**
  * Keeps track of which tasks are currently being tracked,
  * by associating an event identifier to each of them.
@@ -23,6 +67,29 @@ struct {
     __uint(map_flags, BPF_F_NO_PREALLOC);
 } traced_pids SEC(".maps");
 
+/**
+ * Netfilter hooks currently running in each task. The hooks of the softirqs interrupting
+ * a task nest on top of its own, as they are over by the time the task resumes, while
+ * its own hooks follow it when it is preempted or migrated to another cpu.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_TASK_STORAGE);
+    __type(key, u32);
+    __type(value, struct per_task_nf_hooks);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} task_nf_hooks SEC(".maps");
+
+/**
+ * Event to resume once busy polling is over, for each task
+ * currently busy polling.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_TASK_STORAGE);
+    __type(key, u32);
+    __type(value, u64);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} busy_poll_saved_events SEC(".maps");
+
 /**
  * Per-cpu timestamps and counters
  */
@@ -33,60 +100,434 @@ struct {
     __uint(max_entries, 1);
 } per_cpu SEC(".maps");
 
+/**
+ * Per-cgroup counters, keyed by cgroup id.
+ * 
+ * Only the events that run on behalf of a specific task
+ * (syscalls and io workers) are accounted here. Entries for
+ * cgroups that no longer exist are removed by the user-space.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __type(key, u64);
+    __type(value, struct per_cgroup_data);
+    __uint(max_entries, 1024);
+} per_cgroup SEC(".maps");
+
+/**
+ * Per-process counters, keyed by TGID.
+ * 
+ * Like `per_cgroup`, only task-bound events are accounted here.
+ * The LRU policy bounds the number of tracked processes, evicting
+ * the ones that have been idle the longest.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __type(key, u32);
+    __type(value, struct per_process_data);
+    __uint(max_entries, 4096);
+} per_process SEC(".maps");
+
+/**
+ * Per-cpu time in ns spent in the handlers of the IRQs of
+ * the network devices, keyed by IRQ number.
+ * 
+ * The user-space populates this map with the IRQs of all the
+ * network devices at startup, and only those IRQs are accounted.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __type(key, u32);
+    __type(value, u64);
+    __uint(max_entries, 4096);
+} net_irqs SEC(".maps");
+
 /**
  * Buffer with all the captured stack traces.
- * The buffer is logically split into two equal-sized slots,
- * that are swapped by the user-space just before each update.
+ * The buffer is split into one region for each cpu, and each region
+ * is further split into two equal-sized slots of `stack_traces_slot_entries`
+ * entries, that are swapped by the user-space just before each update.
+ * Entry `i` of slot `s` of cpu `c` is therefore at index
+ * `(c*2 + s)*stack_traces_slot_entries + i`.
  * 
  * Each element of the array encodes:
- *   - trace size in bytes (32 MSbits) | cpuid (32 LSbits) in the first u64
- *   - actual trace in the next 127 u64s
+ *   - trace size in bytes (32 MSbits) | event (8 LSbits) in the first u64,
+ *     where event is the one of `event_types` the trace is accounted to
+ *   - name of the device being polled by NAPI, if any, in the next 2 u64s
+ *     (`STACK_TRACE_DEV_NAME_OFF`)
+ *   - netfilter hook the trace was sampled in, as `NF_HOOK_STATE` or 0 if none,
+ *     in the next u64 (`STACK_TRACE_NF_HOOK_OFF`)
+ *   - names of the netfilter table and chain being evaluated, if any, in the
+ *     next 4 u64s, `NF_CHAIN_NAME_LEN` bytes each (`STACK_TRACE_NF_CHAIN_OFF`)
+ *   - actual trace in the remaining 120 u64s (`STACK_TRACE_FRAMES_OFF`)
  * 
  * The array is mmapable to allow fast access from user-space
  * without the need for expensive syscalls.
+ * 
+ * This is only the template for the actual buffers, that are created
+ * by the user-space with the right size, and possibly re-created with
+ * a larger size if samples are being dropped.
+ */
+struct stack_traces_buf {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(map_flags, BPF_F_MMAPABLE | BPF_F_INNER_MAP);
+	__uint(key_size, sizeof(u32));
+	__uint(value_size, sizeof(u64)*STACK_TRACE_ENTRY_LEN);
+    __uint(max_entries, 1);
+};
+
+/**
+ * Holds the current stack traces buffer at index 0
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY_OF_MAPS);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __array(values, struct stack_traces_buf);
+} stack_traces SEC(".maps");
+
+/**
+ * Number of traces present in each slot of each cpu's region
+ * of the `stack_traces` buffer, at index `c*2 + s`.
+ * 
+ * Each counter is only ever incremented by its own cpu,
+ * so no atomics are needed from the bpf side.
+ * The array is mmapable for the user-space to read and reset
+ * the counters without syscalls.
  */
 struct {
 	__uint(type, BPF_MAP_TYPE_ARRAY);
 	__uint(map_flags, BPF_F_MMAPABLE);
 	__uint(key_size, sizeof(u32));
-	__uint(value_size, sizeof(u64)*128);
-    __uint(max_entries, 1); // This is set at runtime based on configuration parameters
-} stack_traces SEC(".maps");
+	__uint(value_size, sizeof(u64));
+    __uint(max_entries, 1); // This is set at runtime to twice the number of cpus
+} stack_traces_count SEC(".maps");
 
 /**
- * Counters of the number of traces present in each slot of
- * the `stack_traces` buffer.
+ * Number of samples that did not fit in each slot of each cpu's
+ * region of the `stack_traces` buffer, at index `c*2 + s`.
  * 
- * Their increment must be atomic from the bpf side
- * as they are shared among all the cpus.
+ * Like `stack_traces_count`, each counter is only ever
+ * incremented by its own cpu.
  */
-u64 stack_traces_count_slot_0 = 0, stack_traces_count_slot_1 = 0;
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(map_flags, BPF_F_MMAPABLE);
+	__uint(key_size, sizeof(u32));
+	__uint(value_size, sizeof(u64));
+    __uint(max_entries, 1); // This is set at runtime to twice the number of cpus
+} stack_traces_dropped SEC(".maps");
+
+/**
+ * Number of entries in each slot of the current `stack_traces` buffer.
+ * This is set by the user-space along with the buffer, 0 if there is none yet.
+ */
+u32 stack_traces_slot_entries = 0;
 
 /**
  * Slot selector into the `stack_traces` map.
  * 
- * The value represents the current offset to be applied to
- * the buffer, and will therefore only ever be 0 or `stack_traces.max_entries/2`.
- * 
  * A non-zero value means select slot1, otherwise use slot0.
  */
-u32 stack_traces_slot_off = 0;
+u32 stack_traces_slot = 0;
+
+/**
+ * Alternative transport for the captured stack traces, used in place
+ * of `stack_traces` when `use_ringbuf` is set.
+ * 
+ * Records have the same layout as the elements of `stack_traces`, except
+ * that the first u64 also holds the cpu id in bits 8 to 31, and that they
+ * are truncated to the actual size of the trace.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 4096); // This is set at runtime according to the sampling frequency
+} stack_traces_ringbuf SEC(".maps");
+
+/**
+ * Per-cpu scratch space to build the `stack_traces_ringbuf` records in
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(key_size, sizeof(u32));
+    __uint(value_size, sizeof(u64)*STACK_TRACE_ENTRY_LEN);
+    __uint(max_entries, 1);
+} ringbuf_scratch SEC(".maps");
+
+/**
+ * When true, stack traces are sent through `stack_traces_ringbuf` rather
+ * than the `stack_traces` buffer. Set by the user-space before loading.
+ */
+const volatile bool use_ringbuf = false;
+
+/**
+ * Address ranges of the kernel symbols of interest, sorted by address
+ * and populated by the user-space at startup for the in-kernel
+ * classification of the stack traces.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __type(key, u32);
+    __type(value, struct ksym_range);
+    __uint(max_entries, KSYM_RANGES_MAX);
+} ksym_ranges SEC(".maps");
+
+/**
+ * Number of valid entries in `ksym_ranges`
+ */
+u32 ksym_ranges_len = 0;
+
+/**
+ * Actions to run on the marks of a stack trace for each symbol of interest,
+ * keyed by breakdown index times `KSYM_RANGES_MAX` plus the symbol's index
+ * in `ksym_ranges`. Populated by the user-space at startup.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __type(key, u32);
+    __type(value, struct ksym_actions);
+    __uint(max_entries, BREAKDOWNS_MAX * KSYM_RANGES_MAX);
+} ksym_actions SEC(".maps");
+
+/**
+ * Actions to run on the marks of a stack trace once all of its frames
+ * were walked, keyed by breakdown index. Populated by the user-space at startup.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __type(key, u32);
+    __type(value, struct ksym_actions);
+    __uint(max_entries, BREAKDOWNS_MAX);
+} finally_actions SEC(".maps");
+
+/**
+ * How the stack traces of each event are classified, keyed by event.
+ * Populated by the user-space at startup.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __type(key, u32);
+    __type(value, struct event_breakdown);
+    __uint(max_entries, EVENT_MAX);
+} event_breakdowns SEC(".maps");
+
+/**
+ * Per-cpu counters of the stack traces classified in-kernel,
+ * keyed by the event the traces are accounted to.
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __type(key, u32);
+    __type(value, struct category_counts);
+    __uint(max_entries, EVENT_MAX);
+} category_counts SEC(".maps");
+
+/**
+ * Per-cpu scratch space for the stack traces classified in-kernel
+ */
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(key_size, sizeof(u32));
+    __uint(value_size, sizeof(u64)*(STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF));
+    __uint(max_entries, 1);
+} ksym_scratch SEC(".maps");
+
+/**
+ * When true, stack traces are classified in-kernel into `category_counts`
+ * rather than copied to the `stack_traces` buffer. Set by the user-space before loading.
+ */
+const volatile bool classify_in_kernel = false;
 
 const u64 event_max = EVENT_MAX;
 
+/**
+ * Whether to account the events to the cgroups of the tasks, which are only
+ * resolved in the unified (v2) hierarchy. Set by the user-space before loading.
+ */
+const volatile bool account_cgroups = false;
+
+/**
+ * Account `t` ns of `event` to the cgroup of the current task
+ */
+/**
+ * Returns the softirqs raised on the current cpu, or all of them if the mask cannot be found
+ */
+inline u32 softirqs_pending(void) {
+    if (bpf_core_field_exists(irq_stat.__softirq_pending) && &irq_stat) {
+        return ((irq_cpustat_t*)bpf_this_cpu_ptr(&irq_stat))->__softirq_pending;
+    } else if (bpf_core_field_exists(pcpu_hot.softirq_pending) && &pcpu_hot) {
+        return ((struct pcpu_hot___netto*)bpf_this_cpu_ptr(&pcpu_hot))->softirq_pending;
+    } else if (&__softirq_pending) {
+        return *(u16*)bpf_this_cpu_ptr(&__softirq_pending);
+    }
+
+    return ~0;
+}
+
+inline void account_cgroup(u64 event, u64 t) {
+    u64 cgroup_id;
+    struct per_cgroup_data* per_cgroup_data, zero = {};
+
+    if (!account_cgroups) return;
+    cgroup_id = bpf_get_current_cgroup_id();
+
+    if (unlikely((per_cgroup_data = bpf_map_lookup_elem(&per_cgroup, &cgroup_id)) == NULL)) {
+        bpf_map_update_elem(&per_cgroup, &cgroup_id, &zero, BPF_NOEXIST);
+        if ((per_cgroup_data = bpf_map_lookup_elem(&per_cgroup, &cgroup_id)) == NULL) return;
+    }
+
+    if (event < EVENT_MAX) per_cgroup_data->per_event_total_time[event] += t;
+}
+
+/**
+ * Account `t` ns of `event` to the process of the current task
+ */
+inline void account_process(u64 event, u64 t) {
+    u32 tgid = bpf_get_current_pid_tgid() >> 32;
+    struct per_process_data* per_process_data, zero = {};
+
+    if (unlikely((per_process_data = bpf_map_lookup_elem(&per_process, &tgid)) == NULL)) {
+        bpf_probe_read_kernel_str(zero.comm, sizeof(zero.comm), bpf_get_current_task_btf()->group_leader->comm);
+        bpf_map_update_elem(&per_process, &tgid, &zero, BPF_NOEXIST);
+        if ((per_process_data = bpf_map_lookup_elem(&per_process, &tgid)) == NULL) return;
+    }
+
+    // The map is shared among all the cpus
+    if (event < EVENT_MAX) __sync_fetch_and_add(&per_process_data->per_event_total_time[event], t);
+}
+
+/**
+ * Account `t` ns of `event` to the current task's cgroup and process
+ */
+inline void account_task(u64 event, u64 t) {
+    account_cgroup(event, t);
+    account_process(event, t);
+}
+
+/**
+ * Get the top-level event `event` is a sub-event of,
+ * or `event` itself if it is already a top-level event
+ */
+inline u64 parent_event(u64 event) {
+    if (event >= EVENT_MAX) return event;
+    if (event >= EVENT_NET_TX_SOFTIRQ_IRQ_TAIL) return EVENT_NET_TX_SOFTIRQ;
+    if (event >= EVENT_NET_RX_SOFTIRQ_IRQ_TAIL) return EVENT_NET_RX_SOFTIRQ;
+    if (event >= EVENT_SOCK_RECVMSG_TCP) return EVENT_SOCK_RECVMSG;
+    if (event >= EVENT_SOCK_SENDMSG_TCP) return EVENT_SOCK_SENDMSG;
+    return event;
+}
+
+/**
+ * Get the event the stack traces of a task currently in `event`
+ * are accounted to, or EVENT_MAX if they are not to be sampled
+ */
+inline u64 task_stack_trace_event(u64 event) {
+    switch (parent_event(event)) {
+    case EVENT_SOCK_SENDMSG:
+        return EVENT_SOCK_SENDMSG;
+
+    case EVENT_RX_BUSY_POLL:
+        return EVENT_RX_BUSY_POLL;
+
+    case EVENT_RX_THREADED_NAPI:
+        return EVENT_RX_THREADED_NAPI;
+
+    default:
+        return EVENT_MAX;
+    }
+}
+
+/**
+ * Enable or disable stack traces for the current task, currently in `event`
+ */
+inline void set_task_stack_traces(struct per_cpu_data* per_cpu_data, u64 event) {
+    u64 stack_trace_event = task_stack_trace_event(event);
+
+    if (stack_trace_event < EVENT_MAX) {
+        per_cpu_data->disable_stack_trace = 0;
+        per_cpu_data->stack_trace_event = stack_trace_event;
+    } else {
+        per_cpu_data->disable_stack_trace = 1;
+    }
+}
+
+/**
+ * Check whether `task` is a kernel thread whose name starts with `prefix`
+ */
+inline bool is_kthread(struct task_struct* task, const char* prefix, int prefix_len) {
+    if (!(task->flags & 0x00200000 /* PF_KTHREAD */)) return false;
+
+    for (int i = 0; i < prefix_len; ++i) {
+        if (task->comm[i] != prefix[i]) return false;
+    }
+
+    return true;
+}
+
+/**
+ * Check whether `task` is one of the ksoftirqd kernel threads
+ */
+inline bool is_ksoftirqd(struct task_struct* task) {
+    const char prefix[] = "ksoftirqd/";
+    return is_kthread(task, prefix, sizeof(prefix) - 1);
+}
+
+/**
+ * Check whether `task` is one of the threaded NAPI kernel threads
+ */
+inline bool is_napi_kthread(struct task_struct* task) {
+    const char prefix[] = "napi/";
+    return is_kthread(task, prefix, sizeof(prefix) - 1);
+}
+
+/**
+ * Classify the context of the softirq about to run into one of `softirq_contexts`
+ */
+inline u8 softirq_context(struct per_cpu_data* per_cpu_data) {
+    if (per_cpu_data->irq_exit_depth) return SOFTIRQ_CTX_IRQ_TAIL;
+    if (is_ksoftirqd(bpf_get_current_task_btf())) return SOFTIRQ_CTX_KSOFTIRQD;
+    return SOFTIRQ_CTX_PROCESS;
+}
+
+/**
+ * Classify the protocol of `sock` into one of `sock_protos`
+ */
+inline enum sock_protos sock_proto(struct socket* sock) {
+    struct sock* sk = sock->sk;
+    u16 family = sk->__sk_common.skc_family;
+
+    if (family == AF_UNIX) return SOCK_PROTO_UNIX;
+    if (family == AF_INET || family == AF_INET6) {
+        switch (sk->sk_protocol) {
+        case IPPROTO_TCP:
+        case IPPROTO_MPTCP:
+            return SOCK_PROTO_TCP;
+
+        case IPPROTO_UDP:
+        case IPPROTO_UDPLITE:
+            return SOCK_PROTO_UDP;
+        }
+    }
+
+    return SOCK_PROTO_OTHER;
+}
+
 inline void stop_event(u64 per_task_events, struct per_cpu_data* per_cpu_data, u64 now) {
-    u64 t;
+    u64 t, parent;
     
     if (per_task_events < EVENT_MAX) {
         t = now - per_cpu_data->entry_ts;
+        parent = parent_event(per_task_events);
         
         per_cpu_data->per_event_total_time[per_task_events] += t;
+        if (parent != per_task_events) per_cpu_data->per_event_total_time[parent] += t;
         per_cpu_data->sched_switch_accounted_time += t;
+        account_task(parent, t);
     }
 }
 
 SEC("fentry/sock_sendmsg")
-int BPF_PROG(sock_sendmsg_entry) {
+int BPF_PROG(sock_sendmsg_entry, struct socket* sock) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
     u64* per_task_events, now = bpf_ktime_get_ns();
@@ -96,7 +537,8 @@ int BPF_PROG(sock_sendmsg_entry) {
         likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
     ) {
         per_cpu_data->entry_ts = now;
-        *per_task_events = EVENT_SOCK_SENDMSG;
+        *per_task_events = EVENT_SOCK_SENDMSG_TCP + sock_proto(sock);
+        set_task_stack_traces(per_cpu_data, *per_task_events);
     }
     
     return 0;
@@ -106,26 +548,23 @@ SEC("fexit/sock_sendmsg")
 int BPF_PROG(sock_sendmsg_exit) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
-    u64* per_task_events, now, entry_ts, t;
+    u64* per_task_events;
 
     if (
         likely((per_task_events = bpf_task_storage_get(&traced_pids, bpf_get_current_task_btf(), NULL, 0)) != NULL) &&
         likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
     ) {
-        entry_ts = per_cpu_data->entry_ts;
-        now = bpf_ktime_get_ns();
-        t = now - entry_ts;
-
+        // Accounts both the protocol sub-event and EVENT_SOCK_SENDMSG
+        stop_event(*per_task_events, per_cpu_data, bpf_ktime_get_ns());
         *per_task_events = EVENT_MAX;
-        per_cpu_data->per_event_total_time[EVENT_SOCK_SENDMSG] += t;
-        per_cpu_data->sched_switch_accounted_time += t;
+        set_task_stack_traces(per_cpu_data, EVENT_MAX);
     }
     
     return 0;
 }
 
 SEC("fentry/sock_recvmsg")
-int BPF_PROG(sock_recvmsg_entry) {
+int BPF_PROG(sock_recvmsg_entry, struct socket* sock) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
     u64* per_task_events, now = bpf_ktime_get_ns();
@@ -135,7 +574,7 @@ int BPF_PROG(sock_recvmsg_entry) {
         likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
     ) {
         per_cpu_data->entry_ts = now;
-        *per_task_events = EVENT_SOCK_RECVMSG;
+        *per_task_events = EVENT_SOCK_RECVMSG_TCP + sock_proto(sock);
     }
     
     return 0;
@@ -145,24 +584,68 @@ SEC("fexit/sock_recvmsg")
 int BPF_PROG(sock_recvmsg_exit) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
-    u64* per_task_events, now, entry_ts, t;
+    u64* per_task_events;
 
     if (
         likely((per_task_events = bpf_task_storage_get(&traced_pids, bpf_get_current_task_btf(), NULL, 0)) != NULL) &&
         likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
     ) {
-        entry_ts = per_cpu_data->entry_ts;
-        now = bpf_ktime_get_ns();
-        t = now - entry_ts;
-
+        // Accounts both the protocol sub-event and EVENT_SOCK_RECVMSG
+        stop_event(*per_task_events, per_cpu_data, bpf_ktime_get_ns());
         *per_task_events = EVENT_MAX;
-        per_cpu_data->per_event_total_time[EVENT_SOCK_RECVMSG] += t;
-        per_cpu_data->sched_switch_accounted_time += t;
     }
     
     return 0;
 }
 
+SEC("fentry/napi_busy_loop")
+int BPF_PROG(napi_busy_loop_entry) {
+    u32 zero = 0;
+    struct task_struct* task = bpf_get_current_task_btf();
+    struct per_cpu_data* per_cpu_data;
+    u64* per_task_events, * saved_event, now = bpf_ktime_get_ns();
+
+    if (
+        likely((per_task_events = bpf_task_storage_get(&traced_pids, task, &event_max, BPF_LOCAL_STORAGE_GET_F_CREATE)) != NULL)          &&
+        likely((saved_event = bpf_task_storage_get(&busy_poll_saved_events, task, &event_max, BPF_LOCAL_STORAGE_GET_F_CREATE)) != NULL) &&
+        likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
+    ) {
+        // Busy polling is accounted on its own, rather than to the syscall it is called from
+        stop_event(*per_task_events, per_cpu_data, now);
+        *saved_event = *per_task_events;
+
+        per_cpu_data->entry_ts = now;
+        *per_task_events = EVENT_RX_BUSY_POLL;
+        set_task_stack_traces(per_cpu_data, *per_task_events);
+    }
+
+    return 0;
+}
+
+SEC("fexit/napi_busy_loop")
+int BPF_PROG(napi_busy_loop_exit) {
+    u32 zero = 0;
+    struct task_struct* task = bpf_get_current_task_btf();
+    struct per_cpu_data* per_cpu_data;
+    u64* per_task_events, * saved_event, now = bpf_ktime_get_ns();
+
+    if (
+        likely((per_task_events = bpf_task_storage_get(&traced_pids, task, NULL, 0)) != NULL)          &&
+        likely((saved_event = bpf_task_storage_get(&busy_poll_saved_events, task, NULL, 0)) != NULL) &&
+        likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
+    ) {
+        stop_event(*per_task_events, per_cpu_data, now);
+
+        // Resume the syscall busy polling was called from, if any
+        *per_task_events = *saved_event;
+        *saved_event = EVENT_MAX;
+        if (*per_task_events != EVENT_MAX) per_cpu_data->entry_ts = now;
+        set_task_stack_traces(per_cpu_data, *per_task_events);
+    }
+
+    return 0;
+}
+
 SEC("tp_btf/softirq_entry")
 int BPF_PROG(net_rx_softirq_entry, unsigned int vec) {
     u32 zero = 0;
@@ -176,7 +659,9 @@ int BPF_PROG(net_rx_softirq_entry, unsigned int vec) {
     ) {
         stop_event(*per_task_events, per_cpu_data, now);
         per_cpu_data->entry_ts = now;
-        if (vec == NET_RX_SOFTIRQ) per_cpu_data->disable_stack_trace = 0;
+        per_cpu_data->softirq_context = softirq_context(per_cpu_data);
+        per_cpu_data->disable_stack_trace = 0;
+        per_cpu_data->stack_trace_event = vec == NET_RX_SOFTIRQ ? EVENT_NET_RX_SOFTIRQ : EVENT_NET_TX_SOFTIRQ;
     }
 
     return 0;
@@ -186,7 +671,7 @@ SEC("tp_btf/softirq_exit")
 int BPF_PROG(net_rx_softirq_exit, unsigned int vec) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
-    u64* per_task_events, now = bpf_ktime_get_ns(), t;
+    u64* per_task_events, now = bpf_ktime_get_ns(), t, context_event;
 
     if (
         (vec == NET_TX_SOFTIRQ || vec == NET_RX_SOFTIRQ)                                                            &&
@@ -204,9 +689,15 @@ int BPF_PROG(net_rx_softirq_exit, unsigned int vec) {
         default:
         case NET_RX_SOFTIRQ:
             per_cpu_data->per_event_total_time[EVENT_NET_RX_SOFTIRQ] += t;
-            per_cpu_data->disable_stack_trace = 1;
         }
 
+        // Execution context sub-event
+        context_event = (vec == NET_TX_SOFTIRQ ? EVENT_NET_TX_SOFTIRQ_IRQ_TAIL : EVENT_NET_RX_SOFTIRQ_IRQ_TAIL) + per_cpu_data->softirq_context;
+        if (context_event < EVENT_MAX) per_cpu_data->per_event_total_time[context_event] += t;
+
+        // Resume sampling the task the softirq interrupted, if needed
+        set_task_stack_traces(per_cpu_data, *per_task_events);
+
         per_cpu_data->sched_switch_accounted_time += t;
         if (*per_task_events != EVENT_MAX) per_cpu_data->entry_ts = now;
     }
@@ -214,22 +705,358 @@ int BPF_PROG(net_rx_softirq_exit, unsigned int vec) {
     return 0;
 }
 
+SEC("fentry/irq_exit_rcu")
+int BPF_PROG(irq_exit_rcu_entry) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)) {
+        per_cpu_data->irq_exit_depth++;
+    }
+
+    return 0;
+}
+
+SEC("fexit/irq_exit_rcu")
+int BPF_PROG(irq_exit_rcu_exit) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) && per_cpu_data->irq_exit_depth) {
+        per_cpu_data->irq_exit_depth--;
+    }
+
+    return 0;
+}
+
+SEC("fentry/irq_exit")
+int BPF_PROG(irq_exit_entry) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)) {
+        per_cpu_data->irq_exit_depth++;
+    }
+
+    return 0;
+}
+
+SEC("fexit/irq_exit")
+int BPF_PROG(irq_exit_exit) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) && per_cpu_data->irq_exit_depth) {
+        per_cpu_data->irq_exit_depth--;
+    }
+
+    return 0;
+}
+
+SEC("tp_btf/sched_wakeup")
+int BPF_PROG(tp_sched_wakeup, struct task_struct* p) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    // Waking up ksoftirqd means the pending softirqs were deferred to it,
+    // only count it when networking softirqs are among them
+    if (
+        !is_ksoftirqd(p) ||
+        unlikely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) == NULL)
+    ) {
+        return 0;
+    }
+
+    if (!(softirqs_pending() & ((1 << NET_RX_SOFTIRQ) | (1 << NET_TX_SOFTIRQ)))) return 0;
+
+    per_cpu_data->softirq_deferrals++;
+
+    return 0;
+}
+
+SEC("tp_btf/irq_handler_entry")
+int BPF_PROG(net_irq_handler_entry, int irq) {
+    u32 zero = 0, key = irq;
+    struct per_cpu_data* per_cpu_data;
+
+    if (
+        bpf_map_lookup_elem(&net_irqs, &key) != NULL &&
+        likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)
+    ) {
+        per_cpu_data->irq_entry_ts = bpf_ktime_get_ns();
+    }
+
+    return 0;
+}
+
+SEC("tp_btf/irq_handler_exit")
+int BPF_PROG(net_irq_handler_exit, int irq) {
+    u32 zero = 0, key = irq;
+    struct per_cpu_data* per_cpu_data;
+    u64* irq_total_time, now = bpf_ktime_get_ns(), t;
+
+    if (
+        (irq_total_time = bpf_map_lookup_elem(&net_irqs, &key)) != NULL       &&
+        likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) &&
+        per_cpu_data->irq_entry_ts != 0
+    ) {
+        t = now - per_cpu_data->irq_entry_ts;
+        per_cpu_data->irq_entry_ts = 0;
+
+        *irq_total_time += t;
+        per_cpu_data->per_event_total_time[EVENT_HW_IRQ] += t;
+        
+        // Exclude the handler's time from whichever event it interrupted
+        per_cpu_data->entry_ts += t;
+        per_cpu_data->sched_switch_accounted_time += t;
+    }
+
+    return 0;
+}
+
+SEC("fentry/__napi_poll")
+int BPF_PROG(napi_poll_entry, struct napi_struct* n) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)) {
+        bpf_probe_read_kernel_str(per_cpu_data->napi_dev_name, sizeof(per_cpu_data->napi_dev_name), n->dev->name);
+    }
+
+    return 0;
+}
+
+SEC("fexit/__napi_poll")
+int BPF_PROG(napi_poll_exit) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+
+    if (likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL)) {
+        per_cpu_data->napi_dev_name[0] = 0;
+    }
+
+    return 0;
+}
+
+/**
+ * Netfilter hooks running in the current task, NULL if it never entered one
+ */
+inline struct per_task_nf_hooks* current_nf_hooks(void) {
+    return bpf_task_storage_get(&task_nf_hooks, bpf_get_current_task_btf(), NULL, 0);
+}
+
+SEC("fentry/nf_hook_slow")
+int BPF_PROG(nf_hook_slow_entry, struct sk_buff* skb, struct nf_hook_state* state) {
+    u32 zero = 0;
+    struct per_cpu_data* per_cpu_data;
+    struct per_task_nf_hooks* nf_hooks;
+    u8 depth;
+
+    if (
+        likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) &&
+        likely((nf_hooks = bpf_task_storage_get(&task_nf_hooks, bpf_get_current_task_btf(), NULL, BPF_LOCAL_STORAGE_GET_F_CREATE)) != NULL)
+    ) {
+        // Hooks can nest, e.g. when a packet is sent from within a hook
+        depth = nf_hooks->nf_hooks_depth++;
+        if (depth < NF_HOOKS_DEPTH_MAX) {
+            nf_hooks->nf_hooks[depth & (NF_HOOKS_DEPTH_MAX - 1)] = NF_HOOK_STATE(state->pf, state->hook);
+            nf_hooks->nf_hooks_event[depth & (NF_HOOKS_DEPTH_MAX - 1)] = per_cpu_data->disable_stack_trace ? EVENT_MAX : per_cpu_data->stack_trace_event;
+            __builtin_memset(nf_hooks->nf_chains[depth & (NF_HOOKS_DEPTH_MAX - 1)], 0, 2*NF_CHAIN_NAME_LEN);
+        }
+    }
+
+    return 0;
+}
+
+SEC("fexit/nf_hook_slow")
+int BPF_PROG(nf_hook_slow_exit) {
+    struct per_task_nf_hooks* nf_hooks;
+
+    if ((nf_hooks = current_nf_hooks()) != NULL && nf_hooks->nf_hooks_depth > 0) {
+        nf_hooks->nf_hooks_depth--;
+    }
+
+    return 0;
+}
+
+/**
+ * Names of the table and chain being evaluated in the innermost netfilter hook of the
+ * current task, each in `NF_CHAIN_NAME_LEN` bytes, or NULL if none or if nested too deep
+ * to be tracked. `hook` is set to the hook, as `NF_HOOK_STATE`.
+ */
+inline char* current_nf_chain(u8* hook) {
+    struct per_task_nf_hooks* nf_hooks;
+    u8 depth;
+
+    if ((nf_hooks = current_nf_hooks()) == NULL) return NULL;
+    depth = nf_hooks->nf_hooks_depth;
+    if (depth == 0 || depth > NF_HOOKS_DEPTH_MAX) return NULL;
+
+    *hook = nf_hooks->nf_hooks[(depth - 1) & (NF_HOOKS_DEPTH_MAX - 1)];
+    return nf_hooks->nf_chains[(depth - 1) & (NF_HOOKS_DEPTH_MAX - 1)];
+}
+
+/**
+ * Clear the names of the table and chain being evaluated in the innermost netfilter hook
+ */
+inline void clear_nf_chain(void) {
+    char* names;
+    u8 hook;
+
+    if ((names = current_nf_chain(&hook)) != NULL) __builtin_memset(names, 0, 2*NF_CHAIN_NAME_LEN);
+}
+
+/**
+ * Only the base chains are entered through `nft_do_chain`, the regular chains
+ * they jump to are evaluated in the same call and accounted to the base chain
+ */
+SEC("fentry/nft_do_chain")
+int BPF_PROG(nft_do_chain_entry, void* pkt, struct nft_chain___netto* chain) {
+    char* names;
+    u8 hook;
+
+    if ((names = current_nf_chain(&hook)) != NULL) {
+        bpf_probe_read_kernel_str(names, NF_CHAIN_NAME_LEN, BPF_CORE_READ(chain, table, name));
+        bpf_probe_read_kernel_str(names + NF_CHAIN_NAME_LEN, NF_CHAIN_NAME_LEN, BPF_CORE_READ(chain, name));
+    }
+
+    return 0;
+}
+
+SEC("fexit/nft_do_chain")
+int BPF_PROG(nft_do_chain_exit) {
+    clear_nf_chain();
+    return 0;
+}
+
+/**
+ * Record the name of the iptables `table` being evaluated in the innermost netfilter hook.
+ * The tables are evaluated as a whole, from the built-in chain of the hook.
+ */
+inline void set_xt_chain(struct xt_table___netto* table) {
+    char* names;
+    u8 hook;
+
+    if ((names = current_nf_chain(&hook)) != NULL) {
+        bpf_probe_read_kernel_str(names, NF_CHAIN_NAME_LEN, &table->name);
+
+        hook = (hook - 1) & 0x7;
+        if (hook < 5) __builtin_memcpy(names + NF_CHAIN_NAME_LEN, xt_chain_names[hook], NF_CHAIN_NAME_LEN);
+    }
+}
+
+/**
+ * Since Linux 5.17, the iptables tables are passed first, as the private data of the hook.
+ * Before, they are passed last, which the `_legacy` programs are loaded for instead.
+ */
+SEC("fentry/ipt_do_table")
+int BPF_PROG(ipt_do_table_entry, struct xt_table___netto* table) {
+    set_xt_chain(table);
+    return 0;
+}
+
+SEC("fentry/ipt_do_table")
+int BPF_PROG(ipt_do_table_legacy_entry, void* skb, void* state, struct xt_table___netto* table) {
+    set_xt_chain(table);
+    return 0;
+}
+
+SEC("fexit/ipt_do_table")
+int BPF_PROG(ipt_do_table_exit) {
+    clear_nf_chain();
+    return 0;
+}
+
+SEC("fentry/ip6t_do_table")
+int BPF_PROG(ip6t_do_table_entry, struct xt_table___netto* table) {
+    set_xt_chain(table);
+    return 0;
+}
+
+SEC("fentry/ip6t_do_table")
+int BPF_PROG(ip6t_do_table_legacy_entry, void* skb, void* state, struct xt_table___netto* table) {
+    set_xt_chain(table);
+    return 0;
+}
+
+SEC("fexit/ip6t_do_table")
+int BPF_PROG(ip6t_do_table_exit) {
+    clear_nf_chain();
+    return 0;
+}
+
+/**
+ * Runtime address of `__task_pid_nr_ns`, to find the KASLR offset of a symbol
+ * table read in place of /proc/kallsyms. Set by `kaslr_anchor`, briefly attached at startup.
+ */
+u64 kaslr_anchor_ip = 0;
+
+SEC("fentry/__task_pid_nr_ns")
+int BPF_PROG(kaslr_anchor) {
+    kaslr_anchor_ip = bpf_get_func_ip(ctx);
+    return 0;
+}
+
+/**
+ * Innermost netfilter hook in `nf_hooks`, as `NF_HOOK_STATE`,
+ * or 0 if none or if nested too deep to be tracked
+ */
+inline u8 current_nf_hook(struct per_task_nf_hooks* nf_hooks) {
+    u8 depth;
+
+    if (nf_hooks == NULL) return 0;
+    depth = nf_hooks->nf_hooks_depth;
+
+    if (depth == 0 || depth > NF_HOOKS_DEPTH_MAX) return 0;
+    return nf_hooks->nf_hooks[(depth - 1) & (NF_HOOKS_DEPTH_MAX - 1)];
+}
+
+/**
+ * Write the names of the table and chain being evaluated in the innermost netfilter hook
+ * in `nf_hooks` to `names`, each in `NF_CHAIN_NAME_LEN` bytes, or leave them empty if none or
+ * if the hook was entered by another event than the one sampled, e.g. by the task a softirq interrupted
+ */
+inline void read_nf_chain(struct per_cpu_data* per_cpu_data, struct per_task_nf_hooks* nf_hooks, char* names) {
+    u8 depth, idx;
+
+    __builtin_memset(names, 0, 2*NF_CHAIN_NAME_LEN);
+    if (nf_hooks == NULL) return;
+    depth = nf_hooks->nf_hooks_depth;
+    if (depth == 0 || depth > NF_HOOKS_DEPTH_MAX) return;
+
+    idx = (depth - 1) & (NF_HOOKS_DEPTH_MAX - 1);
+    if (nf_hooks->nf_hooks_event[idx] != per_cpu_data->stack_trace_event) return;
+
+    __builtin_memcpy(names, nf_hooks->nf_chains[idx], 2*NF_CHAIN_NAME_LEN);
+}
+
 SEC("tp_btf/sched_switch")
 int BPF_PROG(tp_sched_switch, bool preempt, struct task_struct* prev, struct task_struct* next) {
     u32 zero = 0;
     struct per_cpu_data* per_cpu_data;
-    u64* prev_task_events, * next_task_events, now = bpf_ktime_get_ns();
+    u64* prev_task_events, * next_task_events, now = bpf_ktime_get_ns(), t;
     
     prev_task_events = bpf_task_storage_get(&traced_pids, prev, NULL, 0);
-    next_task_events = bpf_task_storage_get(&traced_pids, next, NULL, 0);
+    if (is_napi_kthread(next)) {
+        // Threaded NAPI kthreads are accounted for as long as they run
+        next_task_events = bpf_task_storage_get(&traced_pids, next, &event_max, BPF_LOCAL_STORAGE_GET_F_CREATE);
+        if (next_task_events != NULL) *next_task_events = EVENT_RX_THREADED_NAPI;
+    } else {
+        next_task_events = bpf_task_storage_get(&traced_pids, next, NULL, 0);
+    }
     per_cpu_data     = bpf_map_lookup_elem(&per_cpu, &zero);
 
     if (likely(per_cpu_data != NULL)) {
         if (prev_task_events != NULL) stop_event(*prev_task_events, per_cpu_data, now);
         if (next_task_events != NULL && *next_task_events != EVENT_MAX) per_cpu_data->entry_ts = now;
+        set_task_stack_traces(per_cpu_data, next_task_events != NULL ? *next_task_events : EVENT_MAX);
 
-        if (prev->flags & 0x10 /* PF_IO_WORKER */)
-            per_cpu_data->per_event_total_time[EVENT_IO_WORKER] += now - per_cpu_data->sched_switch_ts - per_cpu_data->sched_switch_accounted_time;
+        if (prev->flags & 0x10 /* PF_IO_WORKER */) {
+            // `prev` is still the current task at this point
+            t = now - per_cpu_data->sched_switch_ts - per_cpu_data->sched_switch_accounted_time;
+            per_cpu_data->per_event_total_time[EVENT_IO_WORKER] += t;
+            account_task(EVENT_IO_WORKER, t);
+        }
         per_cpu_data->sched_switch_ts = now;
         per_cpu_data->sched_switch_accounted_time = 0;
     }
@@ -237,24 +1064,179 @@ int BPF_PROG(tp_sched_switch, bool preempt, struct task_struct* prev, struct tas
     return 0;
 }
 
+/**
+ * Find the symbol of interest `ip` belongs to, as its index in `ksym_ranges`,
+ * or KSYM_RANGES_MAX if none
+ */
+inline u32 ksym_lookup(u64 ip) {
+    u32 lo = 0, hi = ksym_ranges_len, mid;
+    struct ksym_range* range;
+
+    for (int i = 0; i <= KSYM_RANGES_MAX_LOG2 && lo < hi; ++i) {
+        mid = (lo + hi) / 2;
+        if (unlikely((range = bpf_map_lookup_elem(&ksym_ranges, &mid)) == NULL)) break;
+
+        if (ip < range->start) hi = mid;
+        else if (ip >= range->end) lo = mid + 1;
+        else return mid;
+    }
+
+    return KSYM_RANGES_MAX;
+}
+
+#define MARK(m) (1ULL << ((m) & MARKS_MAX))
+
+/**
+ * Run `actions`, in order, on the `marks` of a stack trace.
+ * This mirrors `run_actions` in the user-space.
+ */
+inline void run_ksym_actions(struct ksym_actions* actions, u64* marks) {
+    struct ksym_action* action;
+
+    for (u32 i = 0; i < KSYM_ACTIONS_MAX && i < actions->len; ++i) {
+        action = &actions->actions[i];
+        if ((*marks & action->if_mask) != action->if_mask || (*marks & action->unless_mask)) continue;
+
+        if (action->set) *marks |= MARK(action->mark);
+        else *marks &= ~MARK(action->mark);
+    }
+}
+
+/**
+ * Take the current stack trace and count it in the marks it is given by the breakdown
+ * of the event it is accounted to. This mirrors `KSyms::classify`,
+ * `Breakdown::account_nf_hook` and `Breakdown::finish` in the user-space.
+ */
+inline void classify_stack_trace(struct bpf_perf_event_data* ctx, struct per_cpu_data* per_cpu_data) {
+    u32 zero = 0, id, key, event = per_cpu_data->stack_trace_event;
+    u64* frames, marks = 0;
+    long size;
+    struct event_breakdown* breakdown;
+    struct ksym_actions* actions;
+    struct category_counts* counts;
+
+    if (
+        unlikely((breakdown = bpf_map_lookup_elem(&event_breakdowns, &event)) == NULL) ||
+        !breakdown->enabled ||
+        unlikely((frames = bpf_map_lookup_elem(&ksym_scratch, &zero)) == NULL) ||
+        unlikely((counts = bpf_map_lookup_elem(&category_counts, &event)) == NULL)
+    ) return;
+
+    size = bpf_get_stack(ctx, frames, sizeof(u64)*(STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF), 0);
+    if (size <= 0) return;
+
+    for (u32 i = 0; i < STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF && i < size / sizeof(u64); ++i) {
+        if ((id = ksym_lookup(frames[i])) >= KSYM_RANGES_MAX) continue;
+
+        key = breakdown->breakdown * KSYM_RANGES_MAX + id;
+        if ((actions = bpf_map_lookup_elem(&ksym_actions, &key)) != NULL) run_ksym_actions(actions, &marks);
+    }
+
+    // Netfilter hook the trace was sampled in, if the trace is actually in a hook
+    if (breakdown->nf_hook_mask && (marks & breakdown->nf_hook_mask) == breakdown->nf_hook_mask) {
+        marks |= breakdown->nf_hook_marks[current_nf_hook(current_nf_hooks()) & (NF_HOOK_STATES_MAX - 1)];
+    }
+
+    key = breakdown->breakdown;
+    if ((actions = bpf_map_lookup_elem(&finally_actions, &key)) != NULL) run_ksym_actions(actions, &marks);
+
+    // Traces of the event, but in none of its sub-events
+    if ((marks & breakdown->root_mask) && !(marks & breakdown->metrics_mask)) marks |= MARK(MARKS_MAX);
+
+    for (int i = 0; i <= MARKS_MAX; ++i) {
+        if (marks & MARK(i)) counts->counts[i]++;
+    }
+}
+
+/**
+ * Take the current stack trace and send it through `stack_traces_ringbuf`
+ */
+inline void output_stack_trace(struct bpf_perf_event_data* ctx, struct per_cpu_data* per_cpu_data) {
+    u32 zero = 0;
+    u64* buf;
+    struct per_task_nf_hooks* nf_hooks;
+    long size;
+
+    if (unlikely((buf = bpf_map_lookup_elem(&ringbuf_scratch, &zero)) == NULL)) return;
+
+    size = bpf_get_stack(ctx, buf+STACK_TRACE_FRAMES_OFF, sizeof(u64)*(STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF), 0);
+    if (size < 0 || size > sizeof(u64)*(STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF)) size = 0;
+
+    __builtin_memcpy(buf+STACK_TRACE_DEV_NAME_OFF, per_cpu_data->napi_dev_name, sizeof(per_cpu_data->napi_dev_name));
+    nf_hooks = current_nf_hooks();
+    buf[STACK_TRACE_NF_HOOK_OFF] = current_nf_hook(nf_hooks);
+    read_nf_chain(per_cpu_data, nf_hooks, (char*)(buf+STACK_TRACE_NF_CHAIN_OFF));
+    *buf = (u64)per_cpu_data->stack_trace_event |
+           ((u64)bpf_get_smp_processor_id() << 8) |
+           ((u64)size << 32);
+
+    // Only send the actual frames
+    if (bpf_ringbuf_output(&stack_traces_ringbuf, buf, sizeof(u64)*STACK_TRACE_FRAMES_OFF + size, 0) != 0) {
+        per_cpu_data->ringbuf_failures++;
+    }
+}
+
 SEC("perf_event")
 int perf_event_prog(struct bpf_perf_event_data* ctx) {
     struct per_cpu_data* per_cpu_data;
-    u32 index, zero = 0;
-    u64* buf;
+    struct per_task_nf_hooks* nf_hooks;
+    u32 index, count_index, zero = 0;
+    u64* buf, * count, * dropped;
+    void* traces;
+
+    if (classify_in_kernel) {
+        if (
+            likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) &&
+            !per_cpu_data->disable_stack_trace
+        ) {
+            classify_stack_trace(ctx, per_cpu_data);
+        }
+
+        return 0;
+    }
+
+    if (use_ringbuf) {
+        if (
+            likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) &&
+            !per_cpu_data->disable_stack_trace
+        ) {
+            output_stack_trace(ctx, per_cpu_data);
+        }
+
+        return 0;
+    }
     
     if (
         likely((per_cpu_data = bpf_map_lookup_elem(&per_cpu, &zero)) != NULL) &&
-        !per_cpu_data->disable_stack_trace
+        !per_cpu_data->disable_stack_trace                                      &&
+        likely((traces = bpf_map_lookup_elem(&stack_traces, &zero)) != NULL)    &&
+        likely(stack_traces_slot_entries > 0)
     ) {
-        index = __sync_fetch_and_add(
-            stack_traces_slot_off ? &stack_traces_count_slot_1 : &stack_traces_count_slot_0,
-            1
-        ) + stack_traces_slot_off;
+        count_index = bpf_get_smp_processor_id()*2 + (stack_traces_slot ? 1 : 0);
         
-        if (likely((buf = bpf_map_lookup_elem(&stack_traces, &index)) != NULL)) {
-            *buf = (u64)bpf_get_smp_processor_id() |
-                   ((u64)bpf_get_stack(ctx, buf+1, sizeof(u64)*127, 0) << 32);
+        if (
+            unlikely((count = bpf_map_lookup_elem(&stack_traces_count, &count_index)) == NULL) ||
+            unlikely((dropped = bpf_map_lookup_elem(&stack_traces_dropped, &count_index)) == NULL)
+        ) return 0;
+        
+        index = count_index*stack_traces_slot_entries + *count;
+        
+        if (
+            likely(*count < stack_traces_slot_entries) &&
+            likely((buf = bpf_map_lookup_elem(traces, &index)) != NULL)
+        ) {
+            __builtin_memcpy(buf+STACK_TRACE_DEV_NAME_OFF, per_cpu_data->napi_dev_name, sizeof(per_cpu_data->napi_dev_name));
+            nf_hooks = current_nf_hooks();
+            buf[STACK_TRACE_NF_HOOK_OFF] = current_nf_hook(nf_hooks);
+            read_nf_chain(per_cpu_data, nf_hooks, (char*)(buf+STACK_TRACE_NF_CHAIN_OFF));
+            *buf = (u64)per_cpu_data->stack_trace_event |
+                   ((u64)bpf_get_stack(ctx, buf+STACK_TRACE_FRAMES_OFF, sizeof(u64)*(STACK_TRACE_ENTRY_LEN-STACK_TRACE_FRAMES_OFF), 0) << 32);
+
+            // Only publish the trace once it's complete
+            (*count)++;
+        } else {
+            // The slot is full: the user-space is late
+            (*dropped)++;
         }
     }
 
@@ -3,14 +3,145 @@
 
 #include "vmlinux.h"
 
+/// @brief Size in u64s of each entry of the `stack_traces` map
+#define STACK_TRACE_ENTRY_LEN    128
+/// @brief Offset in u64s of the name of the polled device in a `stack_traces` entry
+#define STACK_TRACE_DEV_NAME_OFF 1
+/// @brief Offset in u64s of the netfilter hook running in a `stack_traces` entry, as `NF_HOOK_STATE`
+#define STACK_TRACE_NF_HOOK_OFF  3
+/// @brief Offset in u64s of the names of the netfilter table and chain being evaluated in a `stack_traces` entry
+#define STACK_TRACE_NF_CHAIN_OFF 4
+/// @brief Offset in u64s of the first frame in a `stack_traces` entry
+#define STACK_TRACE_FRAMES_OFF   8
+
+/// @brief Maximum number of entries of the `ksym_ranges` map
+#define KSYM_RANGES_MAX          256
+/// @brief Number of steps needed to binary search `KSYM_RANGES_MAX` ranges
+#define KSYM_RANGES_MAX_LOG2     9
+/// @brief Maximum number of actions run on each symbol, and once per trace, when classifying in-kernel
+#define KSYM_ACTIONS_MAX         16
+/// @brief Maximum number of breakdowns of the stack traces classified in-kernel
+#define BREAKDOWNS_MAX           4
+/// @brief Maximum number of marks of a stack trace, the next bit marks the unclassified traces
+#define MARKS_MAX                63
+
+/// @brief Maximum nesting level of the netfilter hooks tracked for each task
+#define NF_HOOKS_DEPTH_MAX       4
+/// @brief Number of distinct values of `NF_HOOK_STATE`
+#define NF_HOOK_STATES_MAX       128
+/// @brief Identifies the netfilter hook `hook` of the protocol family `pf`, 0 meaning none
+#define NF_HOOK_STATE(pf, hook)  (1 + ((pf) & 0xF) * 8 + ((hook) & 0x7))
+/// @brief Length of the names of the netfilter tables and chains in a `stack_traces` entry, truncated if longer
+#define NF_CHAIN_NAME_LEN        16
+
 enum event_types {
-    EVENT_SOCK_SENDMSG   = 0,
-    EVENT_SOCK_RECVMSG   = 1,
-    EVENT_NET_TX_SOFTIRQ = 2,
-    EVENT_NET_RX_SOFTIRQ = 3,
-    EVENT_IO_WORKER      = 4,
+    EVENT_SOCK_SENDMSG     = 0,
+    EVENT_SOCK_RECVMSG     = 1,
+    EVENT_NET_TX_SOFTIRQ   = 2,
+    EVENT_NET_RX_SOFTIRQ   = 3,
+    EVENT_IO_WORKER        = 4,
+    EVENT_HW_IRQ           = 5,
+    EVENT_RX_BUSY_POLL     = 6,
+    EVENT_RX_THREADED_NAPI = 7,
+
+    // Sub-events of EVENT_SOCK_SENDMSG, in the same order as `sock_protos`
+    EVENT_SOCK_SENDMSG_TCP   = 8,
+    EVENT_SOCK_SENDMSG_UDP   = 9,
+    EVENT_SOCK_SENDMSG_UNIX  = 10,
+    EVENT_SOCK_SENDMSG_OTHER = 11,
+
+    // Sub-events of EVENT_SOCK_RECVMSG, in the same order as `sock_protos`
+    EVENT_SOCK_RECVMSG_TCP   = 12,
+    EVENT_SOCK_RECVMSG_UDP   = 13,
+    EVENT_SOCK_RECVMSG_UNIX  = 14,
+    EVENT_SOCK_RECVMSG_OTHER = 15,
+
+    // Sub-events of EVENT_NET_RX_SOFTIRQ, in the same order as `softirq_contexts`
+    EVENT_NET_RX_SOFTIRQ_IRQ_TAIL  = 16,
+    EVENT_NET_RX_SOFTIRQ_PROCESS   = 17,
+    EVENT_NET_RX_SOFTIRQ_KSOFTIRQD = 18,
+
+    // Sub-events of EVENT_NET_TX_SOFTIRQ, in the same order as `softirq_contexts`
+    EVENT_NET_TX_SOFTIRQ_IRQ_TAIL  = 19,
+    EVENT_NET_TX_SOFTIRQ_PROCESS   = 20,
+    EVENT_NET_TX_SOFTIRQ_KSOFTIRQD = 21,
+
+    EVENT_MAX              = 22
+};
+
+/// @brief Protocol families socket syscalls are split into
+enum sock_protos {
+    SOCK_PROTO_TCP   = 0,
+    SOCK_PROTO_UDP   = 1,
+    SOCK_PROTO_UNIX  = 2,
+    SOCK_PROTO_OTHER = 3
+};
+
+/// @brief Execution contexts softirqs are split into
+enum softirq_contexts {
+    /// @brief On the way out of a hardware IRQ
+    SOFTIRQ_CTX_IRQ_TAIL  = 0,
+    /// @brief In the calling task, e.g. when re-enabling bottom halves
+    SOFTIRQ_CTX_PROCESS   = 1,
+    /// @brief Deferred to the ksoftirqd thread
+    SOFTIRQ_CTX_KSOFTIRQD = 2
+};
+
+/// @brief Address range of a kernel symbol of interest, as an element of `ksym_ranges`
+struct ksym_range {
+    /// @brief First address of the symbol
+    u64 start;
+
+    /// @brief First address past the end of the symbol
+    u64 end;
+};
+
+/// @brief Action run on the marks of a stack trace, as defined in the categories file
+struct ksym_action {
+    /// @brief Marks that must all be set for the action to run
+    u64 if_mask;
+
+    /// @brief Marks that must all be clear for the action to run
+    u64 unless_mask;
+
+    /// @brief Mark set or cleared by the action
+    u32 mark;
+
+    /// @brief Whether the action sets rather than clears `mark`
+    u32 set;
+};
+
+struct ksym_actions {
+    /// @brief Number of valid entries in `actions`
+    u64 len;
+
+    struct ksym_action actions[KSYM_ACTIONS_MAX];
+};
+
+/// @brief How the stack traces of an event are classified, as an element of `event_breakdowns`
+struct event_breakdown {
+    /// @brief Root mark of the traces of the event
+    u64 root_mask;
 
-    EVENT_MAX            = 5
+    /// @brief Marks of all the sub-events of the breakdown
+    u64 metrics_mask;
+
+    /// @brief Marks the traces must all have to be given the marks of their netfilter hook, 0 if none
+    u64 nf_hook_mask;
+
+    /// @brief Index of the breakdown in the categories file
+    u32 breakdown;
+
+    /// @brief Whether the traces of the event are classified at all
+    u32 enabled;
+
+    /// @brief Marks given to the traces sampled in each netfilter hook, indexed by `NF_HOOK_STATE`
+    u64 nf_hook_marks[NF_HOOK_STATES_MAX];
+};
+
+struct category_counts {
+    /// @brief Number of stack traces with each mark, the unclassified ones at `MARKS_MAX`
+    u64 counts[MARKS_MAX + 1];
 };
 
 struct per_cpu_data {
@@ -23,11 +154,61 @@ struct per_cpu_data {
     /// @brief Total CPU time accounted to various events since the last scheduler switch
     u64 sched_switch_accounted_time;
 
+    /// @brief Entry timestamp of the network IRQ handler currently running, 0 if none
+    u64 irq_entry_ts;
+
     /// @brief Total time in ns registered for each event
     u64 per_event_total_time[EVENT_MAX];
 
+    /// @brief Total number of times ksoftirqd was woken up to run pending NET_RX or NET_TX softirqs
+    u64 softirq_deferrals;
+
+    /// @brief Total number of stack traces that could not be sent through `stack_traces_ringbuf`
+    u64 ringbuf_failures;
+
+    /// @brief Nesting level of the IRQ exit paths currently running
+    u8 irq_exit_depth;
+
+    /// @brief One of `softirq_contexts`, for the softirq currently running
+    u8 softirq_context;
+
     /// @brief When non-zero, stack traces by the perf event prog are disabled
     u8 disable_stack_trace;
+
+    /// @brief Event the stack traces by the perf event prog are currently accounted to
+    u8 stack_trace_event;
+
+    /// @brief Name of the device currently being polled by NAPI, empty if none
+    char napi_dev_name[16];
+};
+
+struct per_task_nf_hooks {
+    /// @brief Netfilter hooks currently running in the task or in the softirqs interrupting it,
+    /// as `NF_HOOK_STATE`, the innermost last
+    u8 nf_hooks[NF_HOOKS_DEPTH_MAX];
+
+    /// @brief Number of netfilter hooks currently running, possibly more than `NF_HOOKS_DEPTH_MAX`
+    u8 nf_hooks_depth;
+
+    /// @brief `stack_trace_event` when each of the hooks in `nf_hooks` was entered, EVENT_MAX if not sampled
+    u8 nf_hooks_event[NF_HOOKS_DEPTH_MAX];
+
+    /// @brief Names of the table and chain being evaluated in each of the hooks in `nf_hooks`,
+    /// each in `NF_CHAIN_NAME_LEN` bytes, empty if none
+    char nf_chains[NF_HOOKS_DEPTH_MAX][2*NF_CHAIN_NAME_LEN];
+};
+
+struct per_cgroup_data {
+    /// @brief Total time in ns registered for each event by the tasks in this cgroup
+    u64 per_event_total_time[EVENT_MAX];
+};
+
+struct per_process_data {
+    /// @brief Total time in ns registered for each event by the threads of this process
+    u64 per_event_total_time[EVENT_MAX];
+
+    /// @brief Command name of the process' main thread
+    char comm[16];
 };
 
 #endif
@@ -0,0 +1,597 @@
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+use anyhow::{anyhow, bail};
+use metrics_common::SampleStats;
+use serde::Deserialize;
+use crate::{ksyms::{BpfProg, BpfProgKind}, common::{event_types_EVENT_NET_RX_SOFTIRQ, event_types_EVENT_RX_BUSY_POLL, event_types_EVENT_RX_THREADED_NAPI, event_types_EVENT_NET_TX_SOFTIRQ, event_types_EVENT_SOCK_SENDMSG, MARKS_MAX, NF_HOOK_STATES_MAX}};
+
+/// Built-in categories, used unless a categories file is given
+const DEFAULT_CATEGORIES: &str = include_str!("../categories.toml");
+
+/// Mark given to the traces with the root mark of their event,
+/// but with none of the marks of the metrics of their breakdown
+pub const UNCLASSIFIED_MARK: u64 = 1 << MARKS_MAX;
+
+/// Events whose stack traces can be broken down, with their metric names
+pub const BREAKDOWN_EVENTS: [(u32, &str); 5] = [
+    (event_types_EVENT_NET_RX_SOFTIRQ,   "RX softirq"),
+    (event_types_EVENT_RX_BUSY_POLL,     "RX busy poll"),
+    (event_types_EVENT_RX_THREADED_NAPI, "RX threaded NAPI"),
+    (event_types_EVENT_NET_TX_SOFTIRQ,   "TX softirq"),
+    (event_types_EVENT_SOCK_SENDMSG,     "TX syscalls")
+];
+
+/// Netfilter hooks, by protocol family and hook number, with the mark Netto gives
+/// to the traces sampled in each of them, on top of the `nf_hook` mark
+const NF_HOOKS: [(u8, u8, &str); 12] = [
+    (2,  0, "nf_prerouting_v4"),
+    (2,  1, "nf_local_in_v4"),
+    (2,  2, "nf_forward_v4"),
+    (2,  3, "nf_local_out_v4"),
+    (2,  4, "nf_postrouting_v4"),
+    (10, 0, "nf_prerouting_v6"),
+    (10, 1, "nf_local_in_v6"),
+    (10, 2, "nf_forward_v6"),
+    (10, 3, "nf_local_out_v6"),
+    (10, 4, "nf_postrouting_v6"),
+    (5,  0, "nf_netdev_ingress"),
+    (5,  1, "nf_netdev_egress")
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CategoriesFile {
+    breakdowns: Vec<BreakdownDef>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BreakdownDef {
+    events: BTreeMap<String, String>,
+    other: Option<String>,
+    device: Option<String>,
+    #[serde(default)]
+    symbols: Vec<SymbolDef>,
+    #[serde(default)]
+    finally: Vec<ActionDef>,
+    metrics: Vec<MetricDef>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SymbolDef {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    modules: Vec<String>,
+    actions: Vec<ActionDef>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActionDef {
+    set: Option<String>,
+    clear: Option<String>,
+    #[serde(default, rename = "if")]
+    if_marks: Vec<String>,
+    #[serde(default)]
+    unless: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricDef {
+    name: String,
+    mark: String
+}
+
+/// Action run on the marks of a stack trace when one of its frames
+/// lands in a symbol of interest, or once the whole trace was walked
+#[derive(Clone, Copy)]
+pub struct Action {
+    /// Marks that must all be set for the action to run
+    pub if_mask: u64,
+    /// Marks that must all be clear for the action to run
+    pub unless_mask: u64,
+    /// Mark set or cleared by the action
+    pub mark: u32,
+    /// Whether the action sets rather than clears `mark`
+    pub set: bool
+}
+
+/// Breakdown of the stack traces of one or more events into
+/// sub-events, as defined in the categories file
+pub struct Breakdown {
+    /// Names of the marks, indexed by mark
+    marks: Vec<String>,
+
+    /// Actions to run for each entry of the `symbols` section
+    rules: Vec<Vec<Action>>,
+
+    /// Index in `rules` of each symbol of interest, keyed by symbol name
+    symbols: HashMap<String, usize>,
+
+    /// Index in `rules` of each kernel module of interest, keyed by module name
+    modules: HashMap<String, usize>,
+
+    /// Actions to run once the whole trace was walked
+    finally: Vec<Action>,
+
+    /// Events broken down, with the root mark of their traces
+    events: Vec<(u32, u32)>,
+
+    /// Names of the sub-events, with the mark of their traces
+    metrics: Vec<(String, u32)>,
+
+    /// Name of the sub-event of the unclassified traces, if reported
+    other: Option<String>,
+
+    /// Mark of the traces accounted to the device being polled, if any
+    device: Option<u32>,
+
+    /// Marks the traces must all have to be accounted to the netfilter hook they
+    /// were sampled in, 0 if they are not
+    nf_hook_mask: u64,
+
+    /// Marks given to the traces sampled in each netfilter hook, indexed by `nf_hook_state`
+    nf_hook_marks: Vec<u64>
+}
+
+/// Counts of the stack traces with each of the marks of a `Breakdown`,
+/// followed by the count of the unclassified traces
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct Counts(Vec<u16>);
+
+/// Load the breakdowns from the categories file at `path`, or the built-in ones if None
+pub fn load(path: Option<&Path>) -> anyhow::Result<Vec<Breakdown>> {
+    let contents = match path {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read the categories file {}: {e}", path.display()))?,
+        None => DEFAULT_CATEGORIES.to_string()
+    };
+
+    let file: CategoriesFile = toml::from_str(&contents)
+        .map_err(|e| anyhow!("Invalid categories file: {e}"))?;
+
+    let breakdowns = file.breakdowns
+        .into_iter()
+        .map(Breakdown::new)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Each event can only be broken down once
+    for (event, name) in BREAKDOWN_EVENTS {
+        if breakdowns.iter().filter(|b| b.root(event).is_some()).count() > 1 {
+            bail!("Event \"{name}\" is in more than one breakdown");
+        }
+    }
+
+    Ok(breakdowns)
+}
+
+impl Breakdown {
+    fn new(def: BreakdownDef) -> anyhow::Result<Self> {
+        let mut marks = vec![];
+
+        // Marks are declared by their first use
+        let mut mark = |name: &str| -> anyhow::Result<u32> {
+            if let Some(idx) = marks.iter().position(|m| m == name) {
+                return Ok(idx as u32);
+            }
+            if marks.len() >= MARKS_MAX as usize {
+                bail!("Too many marks in a breakdown, at most {MARKS_MAX} are supported");
+            }
+
+            marks.push(name.to_string());
+            Ok(marks.len() as u32 - 1)
+        };
+
+        let mut action = |def: &ActionDef| -> anyhow::Result<Action> {
+            let (set, name) = match (&def.set, &def.clear) {
+                (Some(name), None) => (true, name),
+                (None, Some(name)) => (false, name),
+                _ => bail!("Each action must either set or clear exactly one mark")
+            };
+
+            Ok(Action {
+                if_mask: def.if_marks.iter().try_fold(0, |mask, m| Ok::<_, anyhow::Error>(mask | 1 << mark(m)?))?,
+                unless_mask: def.unless.iter().try_fold(0, |mask, m| Ok::<_, anyhow::Error>(mask | 1 << mark(m)?))?,
+                mark: mark(name)?,
+                set
+            })
+        };
+
+        let mut rules = vec![];
+        let mut symbols = HashMap::new();
+        let mut modules = HashMap::new();
+        for symbol in &def.symbols {
+            if symbol.names.is_empty() && symbol.modules.is_empty() {
+                bail!("Each entry of symbols must list at least one symbol or module name");
+            }
+
+            rules.push(symbol.actions.iter().map(&mut action).collect::<anyhow::Result<Vec<_>>>()?);
+
+            for name in &symbol.names {
+                if symbols.insert(name.clone(), rules.len() - 1).is_some() {
+                    bail!("Symbol {name} is listed more than once in a breakdown");
+                }
+            }
+            for name in &symbol.modules {
+                if modules.insert(name.clone(), rules.len() - 1).is_some() {
+                    bail!("Module {name} is listed more than once in a breakdown");
+                }
+            }
+        }
+
+        let finally = def.finally.iter().map(&mut action).collect::<anyhow::Result<Vec<_>>>()?;
+
+        let events = def.events
+            .iter()
+            .map(|(name, root)| -> anyhow::Result<_> {
+                let (event, _) = BREAKDOWN_EVENTS
+                    .iter()
+                    .find(|(_, n)| *n == name.as_str())
+                    .ok_or_else(|| anyhow!("Unknown event \"{name}\" in the categories file"))?;
+
+                Ok((*event, mark(root)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let metrics = def.metrics
+            .iter()
+            .map(|m| -> anyhow::Result<_> { Ok((m.name.clone(), mark(&m.mark)?)) })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let device = def.device.as_deref().map(&mut mark).transpose()?;
+
+        // The netfilter hooks are only accounted if the breakdown tells which traces are in
+        // one through the `nf_hook_slow` mark, and only to the hooks whose marks it uses
+        let mask_of = |name: &str| marks.iter().position(|m| m == name).map_or(0, |idx| 1 << idx);
+        let nf_hook_mask = mask_of("nf_hook_slow");
+        let mut nf_hook_marks = vec![0; NF_HOOK_STATES_MAX as usize];
+        if nf_hook_mask != 0 {
+            nf_hook_marks[1..].fill(mask_of("nf_hook"));
+            for (pf, hook, name) in NF_HOOKS {
+                nf_hook_marks[nf_hook_state(pf, hook) as usize] |= mask_of(name);
+            }
+        }
+
+        Ok(Self {
+            marks,
+            rules,
+            symbols,
+            modules,
+            finally,
+            events,
+            metrics,
+            other: def.other,
+            device,
+            nf_hook_mask,
+            nf_hook_marks
+        })
+    }
+
+    /// Index of the rule to apply when a frame lands in the symbol `name` of `module`,
+    /// or of the core kernel if None, if the symbol is of interest. Symbols listed
+    /// by name take precedence over the modules they belong to.
+    pub fn rule(&self, name: &str, module: Option<&str>) -> Option<usize> {
+        self.symbols
+            .get(name)
+            .or_else(|| self.modules.get(module?))
+            .copied()
+    }
+
+    /// Actions of the `rule`-th rule
+    pub fn actions(&self, rule: usize) -> &[Action] {
+        &self.rules[rule]
+    }
+
+    /// Actions to run once the whole trace was walked
+    pub fn finally(&self) -> &[Action] {
+        &self.finally
+    }
+
+    /// Root mark of the traces of `event`, if broken down by this breakdown
+    pub fn root(&self, event: u32) -> Option<u32> {
+        self.events
+            .iter()
+            .find_map(|&(e, root)| (e == event).then_some(root))
+    }
+
+    /// Events broken down by this breakdown, with their root marks
+    pub fn events(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.events.iter().copied()
+    }
+
+    /// Index of the mark `name`, if used in this breakdown
+    pub fn mark(&self, name: &str) -> Option<u32> {
+        self.marks
+            .iter()
+            .position(|m| m == name)
+            .map(|idx| idx as u32)
+    }
+
+    /// Marks of all the sub-events
+    pub fn metrics_mask(&self) -> u64 {
+        self.metrics
+            .iter()
+            .fold(0, |mask, &(_, mark)| mask | 1 << mark)
+    }
+
+    /// Name of the sub-event of the unclassified traces, if reported
+    pub fn other(&self) -> Option<&str> {
+        self.other.as_deref()
+    }
+
+    /// Mark of the traces accounted to the device being polled, if any
+    pub fn device(&self) -> Option<u32> {
+        self.device
+    }
+
+    /// Mark the trace as sampled in the XDP or TC program `prog`, through the
+    /// `xdp_native_prog` and `xdp_generic_prog` marks for XDP programs.
+    /// Returns false if `prog` does not run in any of the known hooks in this trace.
+    pub fn account_bpf_prog(&self, prog: &BpfProg, marks: &mut u64) -> bool {
+        let has = |name| self.mark(name).map_or(false, |mark| *marks & 1 << mark != 0);
+
+        let mark = match prog.kind {
+            BpfProgKind::Xdp if has("do_xdp_generic") => "xdp_generic_prog",
+            BpfProgKind::Xdp => "xdp_native_prog",
+            BpfProgKind::Tc => return has("tcf_classify")
+        };
+
+        if let Some(mark) = self.mark(mark) {
+            *marks |= 1 << mark;
+        }
+
+        true
+    }
+
+    /// Marks the traces must all have to be accounted to the netfilter hook
+    /// they were sampled in, 0 if they are not
+    pub fn nf_hook_mask(&self) -> u64 {
+        self.nf_hook_mask
+    }
+
+    /// Marks given to the traces sampled in each netfilter hook, indexed by `nf_hook_state`
+    pub fn nf_hook_marks(&self) -> &[u64] {
+        &self.nf_hook_marks
+    }
+
+    /// Whether the frames of a trace with `marks` tell it is in a netfilter hook
+    pub fn in_nf_hook(&self, marks: u64) -> bool {
+        self.nf_hook_mask != 0 && marks & self.nf_hook_mask == self.nf_hook_mask
+    }
+
+    /// Mark the trace as sampled in the netfilter hook `nf_hook`, as given by `nf_hook_state`
+    /// or 0 if none, provided that its frames tell it is in the hook
+    pub fn account_nf_hook(&self, nf_hook: u8, marks: &mut u64) {
+        if self.in_nf_hook(*marks) {
+            *marks |= self.nf_hook_marks[nf_hook as usize % NF_HOOK_STATES_MAX as usize];
+        }
+    }
+
+    /// Run the final actions on the `marks` of a trace of `event`, and, if the
+    /// unclassified traces are reported, mark it as unclassified if it has the
+    /// root mark of `event` but none of the marks of the sub-events
+    pub fn finish(&self, event: u32, mut marks: u64) -> u64 {
+        run_actions(&self.finally, &mut marks);
+
+        if let (Some(root), Some(_)) = (self.root(event), &self.other) {
+            if marks & 1 << root != 0 && marks & self.metrics_mask() == 0 {
+                marks |= UNCLASSIFIED_MARK;
+            }
+        }
+
+        marks
+    }
+
+    /// Break down the `cpu_frac` of `event` into its sub-events, based on
+    /// the `counts` of the marks in the traces, over `traces` total traces
+    pub fn breakdown(&self, event: &str, counts: &Counts, traces: u16, cpu_frac: f64) -> Vec<(String, f64, SampleStats)> {
+        let scale = cpu_frac / traces.max(1) as f64;
+
+        self.metrics
+            .iter()
+            .map(|(name, mark)| (name, counts.get(*mark)))
+            .chain(self.other.iter().map(|name| (name, counts.unclassified())))
+            .map(|(name, count)| (
+                format!("{event}/{name}"),
+                count as f64 * scale,
+                SampleStats::new(count as _, traces as _, cpu_frac)
+            ))
+            .collect()
+    }
+
+    /// Empty counts for the marks of this breakdown
+    pub fn counts(&self) -> Counts {
+        Counts(vec![0; self.marks.len() + 1])
+    }
+}
+
+/// Identifier of the netfilter hook `hook` of the protocol family `pf`, as recorded
+/// with the stack traces. This mirrors `NF_HOOK_STATE` in the BPF programs.
+fn nf_hook_state(pf: u8, hook: u8) -> u8 {
+    1 + (pf & 0xF) * 8 + (hook & 0x7)
+}
+
+/// Run `actions`, in order, on the `marks` of a trace
+#[inline]
+pub fn run_actions(actions: &[Action], marks: &mut u64) {
+    for action in actions {
+        if *marks & action.if_mask != action.if_mask || *marks & action.unless_mask != 0 {
+            continue;
+        }
+
+        if action.set {
+            *marks |= 1 << action.mark;
+        } else {
+            *marks &= !(1 << action.mark);
+        }
+    }
+}
+
+impl Counts {
+    /// Number of traces with `mark`
+    pub fn get(&self, mark: u32) -> u16 {
+        self.0.get(mark as usize).copied().unwrap_or_default()
+    }
+
+    /// Number of unclassified traces
+    pub fn unclassified(&self) -> u16 {
+        self.0.last().copied().unwrap_or_default()
+    }
+
+    /// Count a trace with the given `marks`
+    #[inline]
+    pub fn add(&mut self, marks: u64) {
+        let len = self.0.len();
+
+        for (mark, count) in self.0[..len.saturating_sub(1)].iter_mut().enumerate() {
+            *count += (marks >> mark & 1) as u16;
+        }
+        if let Some(count) = self.0.last_mut() {
+            *count += (marks & UNCLASSIFIED_MARK != 0) as u16;
+        }
+    }
+
+    /// Add the counts of `other`, of the same breakdown
+    pub fn merge(&mut self, other: &Counts) {
+        for (count, other_count) in self.0.iter_mut().zip(&other.0) {
+            *count += other_count;
+        }
+    }
+
+    /// Reset all counts to zero
+    pub fn clear(&mut self) {
+        self.0.fill(0);
+    }
+
+    /// Set from the counters of the traces classified in-kernel,
+    /// indexed by mark, with the unclassified traces at `MARKS_MAX`
+    pub fn set_from_kernel(&mut self, c: &[u64]) {
+        let get = |idx: usize| c.get(idx).copied().unwrap_or_default().min(u16::MAX as _) as u16;
+        let len = self.0.len();
+
+        for (mark, count) in self.0[..len.saturating_sub(1)].iter_mut().enumerate() {
+            *count = get(mark);
+        }
+        if let Some(count) = self.0.last_mut() {
+            *count = get(MARKS_MAX as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use super::*;
+
+    /// Symbolized RX softirq traces, with the sub-events each must be accounted to
+    const RX_SOFTIRQ_TRACES: &str = include_str!("../tests/traces/rx_softirq.txt");
+
+    /// A symbolized trace, as parsed by `parse_traces`
+    struct Trace<'a> {
+        description: &'a str,
+        sub_events: BTreeSet<String>,
+        nf_hook: u8,
+        frames: Vec<(&'a str, Option<&'a str>)>
+    }
+
+    /// `nf_hook_state` of the netfilter hook named as in nftables, e.g. "ip prerouting"
+    fn parse_nf_hook(name: &str) -> u8 {
+        let (family, hook) = name.split_once(' ').unwrap();
+        let pf = match family {
+            "ip" => 2,
+            "netdev" => 5,
+            "bridge" => 7,
+            "ip6" => 10,
+            _ => panic!("Unknown netfilter family {family}")
+        };
+        let hook = match hook {
+            "prerouting" | "ingress" => 0,
+            "input" | "egress" => 1,
+            "forward" => 2,
+            "output" => 3,
+            "postrouting" => 4,
+            _ => panic!("Unknown netfilter hook {hook}")
+        };
+
+        nf_hook_state(pf, hook)
+    }
+
+    /// Parse the traces in `contents`, in the format described at the top of the traces files
+    fn parse_traces(contents: &str) -> Vec<Trace<'_>> {
+        contents
+            .split("\n\n")
+            .filter_map(|block| {
+                let mut trace = Trace { description: "", sub_events: BTreeSet::new(), nf_hook: 0, frames: vec![] };
+
+                for line in block.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                    if let Some(comment) = line.strip_prefix('#') {
+                        trace.description = comment.trim();
+                    } else if let Some(sub_event) = line.strip_prefix('=') {
+                        trace.sub_events.insert(sub_event.trim().to_string());
+                    } else if let Some(nf_hook) = line.strip_prefix('@') {
+                        trace.nf_hook = parse_nf_hook(nf_hook.trim());
+                    } else {
+                        let (name, module) = match line.split_once(' ') {
+                            Some((name, module)) => (name, module.strip_prefix('[').and_then(|m| m.strip_suffix(']'))),
+                            None => (line, None)
+                        };
+                        trace.frames.push((name, module));
+                    }
+                }
+
+                (!trace.frames.is_empty()).then_some(trace)
+            })
+            .collect()
+    }
+
+    /// Sub-events of `event` the trace is accounted to by `breakdown`
+    fn sub_events(breakdown: &Breakdown, event: u32, event_name: &str, trace: &Trace) -> BTreeSet<String> {
+        let mut marks = 0;
+        for &(name, module) in &trace.frames {
+            if let Some(rule) = breakdown.rule(name, module) {
+                run_actions(breakdown.actions(rule), &mut marks);
+            }
+        }
+        breakdown.account_nf_hook(trace.nf_hook, &mut marks);
+
+        let mut counts = breakdown.counts();
+        counts.add(breakdown.finish(event, marks));
+
+        breakdown.breakdown(event_name, &counts, 1, 1.0)
+            .into_iter()
+            .filter(|(_, _, samples)| samples.count > 0)
+            .filter_map(|(name, ..)| Some(name.strip_prefix(event_name)?.strip_prefix('/')?.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn builtin_categories() {
+        let breakdowns = load(None).unwrap();
+
+        for (event, name) in BREAKDOWN_EVENTS {
+            assert_eq!(breakdowns.iter().filter(|b| b.root(event).is_some()).count(), 1, "{name}");
+        }
+    }
+
+    #[test]
+    fn rx_softirq_traces() {
+        let breakdowns = load(None).unwrap();
+        let breakdown = breakdowns
+            .iter()
+            .find(|b| b.root(event_types_EVENT_NET_RX_SOFTIRQ).is_some())
+            .unwrap();
+
+        let traces = parse_traces(RX_SOFTIRQ_TRACES);
+        assert!(!traces.is_empty());
+
+        for trace in &traces {
+            assert_eq!(
+                sub_events(breakdown, event_types_EVENT_NET_RX_SOFTIRQ, "RX softirq", trace),
+                trace.sub_events,
+                "{}", trace.description
+            );
+        }
+    }
+}
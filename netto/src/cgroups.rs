@@ -0,0 +1,66 @@
+use std::{collections::HashMap, fs, path::Path, os::unix::fs::MetadataExt, time::{Duration, Instant}};
+
+/// Mount point of the unified cgroup hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Maximum age of a scan of the hierarchy, after which it is rescanned
+/// to find the cgroups removed since, whose ids are otherwise still known
+const RESCAN_PERIOD: Duration = Duration::from_secs(10);
+
+/// Whether the unified (v2) cgroup hierarchy is mounted at `CGROUP_ROOT`, as opposed to a v1
+/// or hybrid hierarchy, in which the ids of the cgroups of the tasks cannot be resolved
+pub fn unified_hierarchy() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// Helper to resolve cgroup ids, as returned by `bpf_get_current_cgroup_id()`,
+/// into their path in the unified hierarchy
+#[derive(Default)]
+pub struct CgroupNames {
+    names: HashMap<u64, String>,
+
+    /// When the hierarchy was last scanned, if ever
+    scanned_at: Option<Instant>
+}
+
+impl CgroupNames {
+    /// Make sure all of the given cgroup ids can be resolved, and that those of removed
+    /// cgroups are not, rescanning the cgroup hierarchy at most once if any of them is
+    /// unknown or if the last scan is older than `RESCAN_PERIOD`
+    pub fn refresh<'a>(&mut self, mut ids: impl Iterator<Item = &'a u64>) {
+        let stale = self.scanned_at.map_or(true, |t| t.elapsed() >= RESCAN_PERIOD);
+
+        if stale || ids.any(|id| !self.names.contains_key(id)) {
+            self.names.clear();
+            Self::scan(Path::new(CGROUP_ROOT), "/", &mut self.names);
+            self.scanned_at = Some(Instant::now());
+        }
+    }
+
+    /// Lookup the path of a cgroup by its id.
+    /// Returns None if the cgroup does not exist (anymore).
+    pub fn get(&self, id: u64) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// Recursively walk the hierarchy starting at `dir`.
+    /// A cgroup's id is the inode number of its directory in cgroupfs.
+    fn scan(dir: &Path, name: &str, names: &mut HashMap<u64, String>) {
+        if let Ok(metadata) = fs::metadata(dir) {
+            names.insert(metadata.ino(), name.to_string());
+        }
+
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    let child_name = format!(
+                        "{}/{}",
+                        name.trim_end_matches('/'),
+                        entry.file_name().to_string_lossy()
+                    );
+                    Self::scan(&entry.path(), &child_name, names);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::{BTreeMap, HashSet}, fs, path::Path};
+
+/// Discover the IRQs of all the network devices in the system, by their
+/// number, along with the name of their action as found in /proc/irq.
+/// Virtual devices without any backing hardware are skipped.
+pub fn net_irqs() -> BTreeMap<u32, String> {
+    let mut irqs = BTreeMap::new();
+    let Ok(devices) = fs::read_dir("/sys/class/net") else {
+        return irqs;
+    };
+
+    for device in devices.flatten() {
+        let device_path = device.path().join("device");
+
+        // Virtio devices get their interrupts through the underlying PCI device
+        let is_virtio = fs::read_link(device_path.join("subsystem"))
+            .map(|s| s.ends_with("virtio"))
+            .unwrap_or(false);
+        let msi_irqs_path = if is_virtio {
+            device_path.join("../msi_irqs")
+        } else {
+            device_path.join("msi_irqs")
+        };
+
+        let msi_irqs = fs::read_dir(msi_irqs_path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|e| e.file_name().to_str()?.parse::<u32>().ok());
+
+        // Legacy INTx interrupt, 0 if none
+        let legacy_irq = fs::read_to_string(device_path.join("irq"))
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .filter(|&irq| irq > 0);
+
+        for irq in msi_irqs.chain(legacy_irq) {
+            irqs.entry(irq).or_insert_with(|| {
+                format!("{}-{irq}", device.file_name().to_string_lossy())
+            });
+        }
+    }
+
+    // Prefer the name of the irq action, when available, but keep names unique
+    let mut names = HashSet::new();
+    for (irq, name) in irqs.iter_mut() {
+        if let Some(action_name) = action_name(*irq) {
+            *name = action_name;
+        }
+
+        if !names.insert(name.clone()) {
+            *name = format!("{name}-{irq}");
+            names.insert(name.clone());
+        }
+    }
+
+    irqs
+}
+
+/// Name of the action registered for `irq`, which /proc/irq exposes as a directory
+fn action_name(irq: u32) -> Option<String> {
+    fs::read_dir(Path::new("/proc/irq").join(irq.to_string()))
+        .ok()?
+        .flatten()
+        .find(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+}
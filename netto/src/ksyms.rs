@@ -1,197 +1,386 @@
-use std::{io::{self, BufReader, BufRead}, fs::File, ops::{Add, AddAssign}, collections::BTreeMap, iter::Sum};
+use std::{io::{self, BufReader, BufRead}, fs::File, path::{Path, PathBuf}, collections::{BTreeMap, BTreeSet, HashMap}, ffi::{CStr, CString}};
+use libbpf_rs::{query::ProgInfoIter, ProgramType, libbpf_sys};
+use crate::{categories::{Action, Breakdown, run_actions}, common};
 #[cfg(feature = "save-traces")]
 use std::io::Write;
 
+/// Number of entries of the instruction pointers cache of each breakdown, as a power of 2
+const IP_CACHE_BITS: u32 = 12;
+
+/// Rule of the instruction pointers cached as not being in any symbol of interest
+const NO_RULE: u32 = u32::MAX;
+
 /// Helper to load and manage application-defined kernel symbols
 #[derive(Default)]
 pub struct KSyms {
-    syms: BTreeMap<u64, KSymsVal>
+    /// Breakdowns of the stack traces, as defined in the categories file
+    breakdowns: Vec<Breakdown>,
+
+    /// Address ranges of the symbols of interest of each breakdown, with the
+    /// index of the rule to apply to them. Adjacent symbols sharing the same
+    /// rule, e.g. those of a module of interest, are merged into a single range.
+    syms: Vec<SymTable<usize>>,
+
+    /// Incremented each time `syms` is reloaded, to invalidate the `IpCache`s
+    generation: u64,
+
+    /// JIT images of the loaded XDP and TC BPF programs
+    bpf_progs: SymTable<BpfProg>,
+
+    /// Ids of the programs in `bpf_progs`
+    bpf_prog_ids: BTreeSet<u32>,
+
+    /// Disjoint address ranges of the symbols of interest of all the breakdowns,
+    /// sorted by address, with the rule of each breakdown that applies to them,
+    /// for the in-kernel classification
+    kernel_ranges: Vec<(u64, u64, Vec<Option<usize>>)>,
+
+    /// Names and load addresses of the kernel modules the symbols were loaded with
+    modules: Vec<(String, String)>,
+
+    /// All the symbols in kallsyms, only loaded on demand for debugging
+    all_syms: BTreeMap<u64, String>,
+
+    /// Symbol table to read in place of /proc/kallsyms, if any
+    kallsyms: Option<PathBuf>,
+
+    /// KASLR offset to add to the addresses in `kallsyms`
+    kallsyms_offset: u64,
+
+    /// Why the breakdowns are unavailable, if the symbol addresses could not be loaded
+    unavailable: Option<String>
+}
+
+/// Direct-mapped cache of the rule of each breakdown the recently seen
+/// instruction pointers fall in, as (ip, rule) pairs.
+/// Most frames of the traces land in a few hot functions, so this
+/// saves most of the lookups into the symbols of interest.
+/// Each thread classifying traces needs its own.
+#[derive(Default)]
+pub struct IpCache {
+    /// `KSyms::generation` the entries were filled in with
+    generation: u64,
+
+    entries: Vec<Vec<(u64, u32)>>
+}
+
+/// Disjoint address ranges sorted by start address, each with a value,
+/// laid out as flat arrays to be looked up by binary search
+struct SymTable<F> {
+    starts: Vec<u64>,
+    ends: Vec<u64>,
+    funs: Vec<F>
 }
 
-type SymbolFun = Box<dyn for<'a> Fn(&'a mut Counts, &'a mut PerFrameProps) -> Option<&'a mut u16>>;
+/// A symbol in /proc/kallsyms
+struct KSym {
+    name: String,
 
-struct KSymsVal {
-    range_end: u64,
-    fun: SymbolFun
+    /// Module the symbol belongs to, None if in the core kernel
+    module: Option<String>
 }
 
-/// Counts instances of symbols in stack traces
-#[derive(Default, Clone, Copy)]
-pub struct Counts {
-    pub net_rx_action: u16,
-    pub __napi_poll: u16,
-    /// Catch-all for any function to submit frames to the network stack
-    pub netif_receive_skb: u16,
-    pub br_handle_frame: u16,
-    /// netif_receive_skb when called by br_handle_frame
-    pub netif_receive_skb_sub_br: u16,
-    pub do_xdp_generic: u16,
-    pub tcf_classify: u16,
-    pub ip_forward: u16,
-    pub ip6_forward: u16,
-    pub ip_local_deliver: u16,
-    pub ip6_input: u16,
-    pub nf_netdev_ingress: u16,
-    pub nf_prerouting_v4: u16,
-    pub nf_prerouting_v6: u16,
-    pub napi_gro_receive_overhead: u16,
-    pub nf_conntrack_in: u16,
-    // pub nf_local_in_v4: u16,
-    // pub nf_local_in_v6: u16,
-    // pub nf_forward_v4: u16,
-    // pub nf_forward_v6: u16
+/// Hook a BPF program of interest is attached to
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BpfProgKind {
+    Xdp,
+    Tc
 }
 
-struct PerFrameProps {
-    in_nf_hook: u16,
-    ip_rcv_finish: u16
+/// A loaded XDP or TC BPF program
+pub struct BpfProg {
+    pub id: u32,
+    pub name: String,
+    pub kind: BpfProgKind
 }
 
 impl KSyms {
-    /// Load requested kernel symbols from /proc/kallsyms
-    pub fn load() -> io::Result<Self> {
-        let mut btree = BTreeMap::new();
-        let f = BufReader::new(File::open("/proc/kallsyms")?);
-        
-        // Load all the addresses into a BTreeMap
-        for line in f.lines() {
-            let line = line?;
-            let parts = line.split_ascii_whitespace().collect::<Vec<_>>();
-            let name = parts[2];
-            let addr = u64::from_str_radix(parts[0], 16)
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, line.clone()))?;
-
-            btree.insert(addr, name.to_string());
+    /// Load the symbols of interest of `breakdowns` from /proc/kallsyms, or from
+    /// the `kallsyms` symbol table if given, along with the runtime address of
+    /// `KASLR_ANCHOR` to relocate it.
+    /// If the addresses in /proc/kallsyms are hidden or inconsistent, the breakdowns
+    /// are marked as unavailable rather than failing, as the other metrics don't
+    /// need them. A `kallsyms` symbol table must be valid instead.
+    pub fn load(breakdowns: Vec<Breakdown>, kallsyms: Option<(PathBuf, u64)>) -> io::Result<Self> {
+        let kallsyms_offset = match &kallsyms {
+            Some((path, anchor_ip)) => kaslr_offset(path, *anchor_ip)?,
+            None => 0
+        };
+        let mut ksyms = Self {
+            breakdowns,
+            modules: read_modules()?,
+            kallsyms: kallsyms.map(|(path, _)| path),
+            kallsyms_offset,
+            ..Default::default()
+        };
+
+        match ksyms.load_syms() {
+            Err(e) if ksyms.kallsyms.is_none() && matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidData) => {
+                ksyms.unavailable = Some(e.to_string());
+            },
+            res => res?
         }
 
+        Ok(ksyms)
+    }
+
+    /// (Re)load the address ranges of the symbols of interest
+    fn load_syms(&mut self) -> io::Result<()> {
+        let btree = read_kallsyms(self.kallsyms.as_deref(), self.kallsyms_offset)?;
+
         // Only keep the symbols we're interested in
-        let syms = btree
+        self.syms = self.breakdowns
             .iter()
-            .filter_map(|(&range_start, name)| {
-                match name.as_str() {
-                    "net_rx_action" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, _| Some(&mut cnt.net_rx_action)
-                    )),
-                    "__napi_poll" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, _| Some(&mut cnt.__napi_poll)
-                    )),
-                    "netif_receive_skb" | "netif_receive_skb_core" | "netif_receive_skb_list_internal" | "__netif_receive_skb" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            cnt.nf_netdev_ingress = cnt.nf_netdev_ingress.max(std::mem::take(in_nf_hook));
-                            Some(&mut cnt.netif_receive_skb)
-                        }
-                    )),
-                    "napi_gro_receive" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            cnt.nf_netdev_ingress = cnt.nf_netdev_ingress.max(std::mem::take(in_nf_hook));
-
-                            if cnt.netif_receive_skb == 0 {
-                                cnt.napi_gro_receive_overhead = 1;
-                            }
-                            
-                            Some(&mut cnt.netif_receive_skb)
-                        }
-                    )),
-                    "do_xdp_generic" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, _| Some(&mut cnt.do_xdp_generic)
-                    )),
-                    "tcf_classify" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, _| Some(&mut cnt.tcf_classify)
-                    )),
-                    "br_handle_frame" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            *in_nf_hook = 0;
-                            cnt.netif_receive_skb_sub_br = std::mem::take(&mut cnt.netif_receive_skb);
-                            Some(&mut cnt.br_handle_frame)
-                        }
-                    )),
-                    "ip_forward" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            *in_nf_hook = 0;
-                            Some(&mut cnt.ip_forward)
-                        }
-                    )),
-                    "ip6_forward" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            *in_nf_hook = 0;
-                            Some(&mut cnt.ip6_forward)
-                        }
-                    )),
-                    "ip_local_deliver" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            *in_nf_hook = 0;
-                            Some(&mut cnt.ip_local_deliver)
-                        }
-                    )),
-                    "ip6_input" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, .. }| {
-                            *in_nf_hook = 0;
-                            Some(&mut cnt.ip6_input)
-                        }
-                    )),
-                    "nf_hook_slow" => Option::<SymbolFun>::Some(Box::new(
-                        |_, PerFrameProps { in_nf_hook, .. }| Some(in_nf_hook)
-                    )),
-                    "ip_rcv" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, ip_rcv_finish, .. }| {
-                            if *ip_rcv_finish == 0 {
-                                cnt.nf_prerouting_v4 = cnt.nf_prerouting_v4.max(*in_nf_hook);
-                            }
-                            *in_nf_hook = 0;
-                            None
-                        }
-                    )),
-                    "ip6_rcv" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, PerFrameProps { in_nf_hook, ip_rcv_finish, ..}| {
-                            if *ip_rcv_finish == 0 {
-                                cnt.nf_prerouting_v6 = cnt.nf_prerouting_v6.max(*in_nf_hook);
-                            }
-                            *in_nf_hook = 0;
-                            None
-                        }
-                    )),
-                    "ip_rcv_finish" | "ip6_rcv_finish" => Option::<SymbolFun>::Some(Box::new(
-                        |_, PerFrameProps { ip_rcv_finish, .. }| Some(ip_rcv_finish)
-                    )),
-                    "nf_conntrack_in" => Option::<SymbolFun>::Some(Box::new(
-                        |cnt, _| Some(&mut cnt.nf_conntrack_in)
-                    )),
-
-                    _ => None
-                }.map(|fun| (range_start, KSymsVal {
-                    range_end: btree
-                        .range(range_start+1..)
-                        .next()
-                        .map(|(&addr, _)| addr)
-                        .unwrap_or(range_start + 1),
-                    fun
+            .map(|breakdown| {
+                let mut syms = SymTable::default();
+                let mut follows_match = false;
+
+                for (&range_start, ksym) in &btree {
+                    let Some(rule) = breakdown.rule(&ksym.name, ksym.module.as_deref()) else {
+                        follows_match = false;
+                        continue;
+                    };
+
+                    // A symbol's range ends where the next one begins
+                    let end = range_end(&btree, range_start);
+
+                    if follows_match && syms.funs.last() == Some(&rule) {
+                        *syms.ends.last_mut().unwrap() = end;
+                    } else {
+                        syms.push(range_start, end, rule);
+                    }
+                    follows_match = true;
+                }
+
+                syms
+            })
+            .collect();
+        self.generation += 1;
+
+        // Split the ranges of all the breakdowns where any of them begins or ends
+        let bounds = self.syms
+            .iter()
+            .flat_map(|syms| syms.iter().flat_map(|(start, end, _)| [start, end]))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        self.kernel_ranges = vec![];
+        for bound in bounds.windows(2) {
+            let (start, end) = (bound[0], bound[1]);
+            let rules = self.syms
+                .iter()
+                .map(|syms| syms.lookup(start).copied())
+                .collect::<Vec<_>>();
+
+            if rules.iter().all(Option::is_none) {
+                continue;
+            }
+
+            match self.kernel_ranges.last_mut() {
+                Some((_, last_end, last_rules)) if *last_end == start && *last_rules == rules => *last_end = end,
+                _ => self.kernel_ranges.push((start, end, rules))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reload the symbols of interest, and all the symbols if loaded,
+    /// if kernel modules were loaded or unloaded since the last call.
+    /// Returns whether the symbols were reloaded.
+    pub fn refresh_modules(&mut self) -> io::Result<bool> {
+        if self.unavailable.is_some() {
+            return Ok(false);
+        }
+
+        let modules = read_modules()?;
+        if modules == self.modules {
+            return Ok(false);
+        }
+
+        self.modules = modules;
+        self.load_syms()?;
+        if !self.all_syms.is_empty() {
+            self.load_all_syms()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Breakdowns of the stack traces, in the order of the categories file
+    pub fn breakdowns(&self) -> &[Breakdown] {
+        &self.breakdowns
+    }
+
+    /// Why the breakdowns are unavailable, None if they are available
+    pub fn unavailable(&self) -> Option<&str> {
+        self.unavailable.as_deref()
+    }
+
+    /// Index of the breakdown of the traces of `event`, with the root mark
+    /// of `event` in it, if the traces of `event` are broken down.
+    /// Always None if the breakdowns are unavailable.
+    pub fn breakdown_of(&self, event: u32) -> Option<(usize, u32)> {
+        if self.unavailable.is_some() {
+            return None;
+        }
+
+        self.breakdowns
+            .iter()
+            .enumerate()
+            .find_map(|(idx, breakdown)| Some((idx, breakdown.root(event)?)))
+    }
+
+    /// Load all the symbols in /proc/kallsyms, to be able to `symbolize` any address
+    pub fn load_all_syms(&mut self) -> io::Result<()> {
+        if self.unavailable.is_some() {
+            return Ok(());
+        }
+
+        self.all_syms = read_kallsyms(self.kallsyms.as_deref(), self.kallsyms_offset)?
+            .into_iter()
+            .map(|(addr, ksym)| (addr, match ksym.module {
+                Some(module) => format!("{} [{module}]", ksym.name),
+                None => ksym.name
+            }))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Name of the symbol `ip` belongs to, if `load_all_syms` was called
+    pub fn symbolize(&self, ip: u64) -> Option<&str> {
+        self.all_syms
+            .range(..=ip)
+            .next_back()
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Address ranges of the symbols of interest, sorted by address,
+    /// to populate the `ksym_ranges` eBPF map with
+    pub fn kernel_ranges(&self) -> impl Iterator<Item = common::ksym_range> + '_ {
+        self.kernel_ranges
+            .iter()
+            .map(|&(start, end, _)| common::ksym_range { start, end })
+    }
+
+    /// Actions to run on each of the `kernel_ranges` for each breakdown, by index
+    /// of the range and of the breakdown, empty if the range is not of interest to it
+    pub fn kernel_actions(&self) -> impl Iterator<Item = (usize, usize, &[Action])> + '_ {
+        self.kernel_ranges
+            .iter()
+            .enumerate()
+            .flat_map(move |(idx, (_, _, rules))| rules
+                .iter()
+                .enumerate()
+                .map(move |(breakdown, rule)| (
+                    idx,
+                    breakdown,
+                    rule.map_or(&[][..], |rule| self.breakdowns[breakdown].actions(rule))
+                ))
+            )
+    }
+
+    /// Reload the JIT images of the XDP and TC BPF programs,
+    /// if the set of loaded programs changed since the last call
+    pub fn refresh_bpf_progs(&mut self) -> io::Result<()> {
+        if self.unavailable.is_some() {
+            return Ok(());
+        }
+
+        let progs = ProgInfoIter::default()
+            .filter_map(|info| {
+                let kind = match info.ty {
+                    ProgramType::Xdp => BpfProgKind::Xdp,
+                    ProgramType::SchedCls | ProgramType::SchedAct => BpfProgKind::Tc,
+                    _ => return None
+                };
+
+                Some((info.tag, (info.id, info.name, kind)))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let prog_ids = progs.values().map(|&(id, ..)| id).collect::<BTreeSet<_>>();
+        if prog_ids == self.bpf_prog_ids {
+            return Ok(());
+        }
+        
+        // JIT images show up in kallsyms as bpf_prog_<tag>_<name>
+        let btree = read_kallsyms(self.kallsyms.as_deref(), self.kallsyms_offset)?;
+        self.bpf_progs = btree
+            .iter()
+            .filter_map(|(&range_start, ksym)| {
+                let (tag, name) = ksym.name.strip_prefix("bpf_prog_")?.split_once('_')?;
+                let tag = (0..tag.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(tag.get(i..i+2)?, 16).ok())
+                    .collect::<Option<Vec<_>>>()?;
+                let (id, info_name, kind) = progs.get(tag.as_slice())?;
+
+                Some((range_start, range_end(&btree, range_start), BpfProg {
+                    id: *id,
+                    // The name in kallsyms is not truncated
+                    name: if name.is_empty() { info_name.clone() } else { name.to_string() },
+                    kind: *kind
                 }))
             })
             .collect();
+        self.bpf_prog_ids = prog_ids;
 
-        Ok(Self { syms })
+        Ok(())
     }
-}
 
-impl Counts {
-    /// Iterate over the frames in the trace and accumulate the instances of the symbols in this Counts
+    /// XDP and TC BPF programs loaded as of the last `refresh_bpf_progs`
+    pub fn bpf_progs(&self) -> impl Iterator<Item = &BpfProg> {
+        self.bpf_progs.iter().map(|(_, _, prog)| prog)
+    }
+
+    /// Find the outermost frame of the trace that lands in the JIT image
+    /// of one of the loaded XDP and TC BPF programs, if any
+    #[inline]
+    pub unsafe fn bpf_prog_from_trace(&self, trace_ptr: *const u64, max_frames: usize) -> Option<&BpfProg> {
+        let mut prog = None;
+        
+        for frame_idx in 0..max_frames {
+            // Load stack frame
+            let ip = trace_ptr.add(frame_idx).read_volatile();
+            if ip == 0 {
+                break;
+            }
+
+            // Frames are ordered from the innermost, so keep the last match
+            if let Some(p) = self.bpf_progs.lookup(ip) {
+                prog = Some(p);
+            }
+        }
+
+        prog
+    }
+
+    /// Iterate over the frames in the trace and run the actions of the symbols
+    /// of interest of the `breakdown`-th breakdown in it, returning the marks of the trace.
+    /// The final actions of the breakdown are not run.
     #[inline]
-    pub unsafe fn acc_trace(
-        &mut self,
-        ksyms: &KSyms,
+    pub unsafe fn classify(
+        &self,
+        breakdown: usize,
+        ip_cache: &mut IpCache,
         trace_ptr: *const u64,
         max_frames: usize,
         #[cfg(feature = "save-traces")]
         mut output: impl Write
-    ) {
+    ) -> u64 {
         #[cfg(feature = "save-traces")]
         let mut first_iter = true;
-        
-        let mut c = Self::default();
-        let mut frame_props = PerFrameProps {
-            in_nf_hook: 0,
-            ip_rcv_finish: 0
-        };
+
+        let mut marks = 0;
+
+        // Drop the entries of symbols since reloaded
+        if ip_cache.generation != self.generation {
+            ip_cache.entries = vec![vec![(0, NO_RULE); 1 << IP_CACHE_BITS]; self.syms.len()];
+            ip_cache.generation = self.generation;
+        }
 
         for frame_idx in 0..max_frames {
             // Load stack frame
@@ -207,62 +396,400 @@ impl Counts {
             }
 
             // Check for known symbols
-            if let Some((_, KSymsVal { range_end, fun })) = ksyms
-                .syms
-                .range(..=ip)
-                .next_back() {
-                    if ip < *range_end {
-                        if let Some(cnt) = fun(&mut c, &mut frame_props) {
-                            *cnt = 1;
-                        }
-                    }
-                }
+            if let Some(rule) = self.rule_of(breakdown, ip_cache, ip) {
+                run_actions(self.breakdowns[breakdown].actions(rule), &mut marks);
+            }
         }
 
         #[cfg(feature = "save-traces")]
         let _ = writeln!(output);
 
-        *self += c;
+        marks
+    }
+
+    /// Rule of the `breakdown`-th breakdown of the symbol of interest `ip` belongs to, if any
+    #[inline]
+    fn rule_of(&self, breakdown: usize, ip_cache: &mut IpCache, ip: u64) -> Option<usize> {
+        // Fibonacci hashing spreads the nearby addresses of a function over the cache
+        let slot = ip.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (u64::BITS - IP_CACHE_BITS);
+        let entry = &mut ip_cache.entries[breakdown][slot as usize];
+
+        // Frames are never at address 0, so empty entries never hit
+        if entry.0 != ip {
+            *entry = (ip, self.syms[breakdown].lookup(ip).map_or(NO_RULE, |&rule| rule as u32));
+        }
+
+        (entry.1 != NO_RULE).then_some(entry.1 as usize)
+    }
+}
+
+impl<F> Default for SymTable<F> {
+    fn default() -> Self {
+        Self { starts: vec![], ends: vec![], funs: vec![] }
+    }
+}
+
+impl<F> FromIterator<(u64, u64, F)> for SymTable<F> {
+    fn from_iter<T: IntoIterator<Item = (u64, u64, F)>>(iter: T) -> Self {
+        let mut table = Self::default();
+        for (start, end, fun) in iter {
+            table.push(start, end, fun);
+        }
+
+        table
+    }
+}
+
+impl<F> SymTable<F> {
+    /// Append a range, which must begin after the end of the last one
+    fn push(&mut self, start: u64, end: u64, fun: F) {
+        debug_assert!(self.ends.last().map_or(true, |&last_end| last_end <= start));
+
+        self.starts.push(start);
+        self.ends.push(end);
+        self.funs.push(fun);
+    }
+
+    /// Iterate over the ranges as (start, end, value) in address order
+    fn iter(&self) -> impl Iterator<Item = (u64, u64, &F)> {
+        self.starts
+            .iter()
+            .zip(&self.ends)
+            .zip(&self.funs)
+            .map(|((&start, &end), fun)| (start, end, fun))
+    }
+
+    /// Find the value of the range `ip` belongs to, if any
+    #[inline]
+    fn lookup(&self, ip: u64) -> Option<&F> {
+        let idx = self.starts.partition_point(|&start| start <= ip).checked_sub(1)?;
+        (ip < self.ends[idx]).then(|| &self.funs[idx])
+    }
+}
+
+/// Kernel function whose runtime address, found by tracing it, anchors the KASLR offset
+const KASLR_ANCHOR: &str = "__task_pid_nr_ns";
+
+/// KASLR offset of the running kernel relative to the `path` symbol table,
+/// from `anchor_ip`, the runtime address of `KASLR_ANCHOR`
+fn kaslr_offset(path: &Path, anchor_ip: u64) -> io::Result<u64> {
+    if anchor_ip == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!(
+            "the runtime address of {KASLR_ANCHOR} to relocate {} could not be found",
+            path.display()
+        )));
+    }
+
+    let anchor = read_kallsyms(Some(path), 0)?
+        .into_iter()
+        .find(|(_, ksym)| ksym.name == KASLR_ANCHOR && ksym.module.is_none())
+        .map(|(addr, _)| addr);
+
+    match anchor {
+        Some(addr) => Ok(anchor_ip.wrapping_sub(addr)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "{} does not match the running kernel: {KASLR_ANCHOR} is missing",
+            path.display()
+        )))
+    }
+}
+
+/// Load all the symbols in /proc/kallsyms, or in the symbol table at `path`
+/// in the same format (e.g. System.map), into a BTreeMap keyed by address
+/// with `offset` added.
+/// Fails if the addresses are hidden or inconsistent.
+fn read_kallsyms(path: Option<&Path>, offset: u64) -> io::Result<BTreeMap<u64, KSym>> {
+    let path = path.unwrap_or(Path::new("/proc/kallsyms"));
+    let mut btree = BTreeMap::new();
+    let f = BufReader::new(File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?);
+
+    // Count the text symbols at address 0 to detect hidden addresses
+    let mut text_syms = 0;
+    let mut zeroed_text_syms = 0;
+    
+    for line in f.lines() {
+        let line = line?;
+        let parts = line.split_ascii_whitespace().collect::<Vec<_>>();
+        let (Some(addr), Some(ty), Some(name)) = (parts.first(), parts.get(1), parts.get(2)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: malformed line: {line}", path.display())));
+        };
+        let addr = u64::from_str_radix(addr, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{}: malformed line: {line}", path.display())))?;
+
+        if ty.eq_ignore_ascii_case("t") {
+            text_syms += 1;
+            if addr == 0 {
+                zeroed_text_syms += 1;
+            }
+        }
+
+        // Module symbols are followed by the module name in brackets
+        let module = parts
+            .get(3)
+            .and_then(|m| m.strip_prefix('[')?.strip_suffix(']'))
+            .map(str::to_string);
+
+        btree.insert(addr.wrapping_add(offset), KSym { name: name.to_string(), module });
+    }
+
+    // Unlike per-CPU symbols, text symbols are never at address 0
+    if text_syms > 0 && zeroed_text_syms == text_syms {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+            "the kernel symbol addresses in {} are hidden, likely because of kernel.kptr_restrict \
+            or of running without CAP_SYSLOG, e.g. in a container. Run Netto with CAP_SYSLOG, \
+            set kernel.kptr_restrict to 0, or pass the symbol table of the running kernel with --kallsyms",
+            path.display()
+        )));
+    } else if zeroed_text_syms > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "{zeroed_text_syms} out of {text_syms} kernel text symbols in {} are at address 0",
+            path.display()
+        )));
+    } else if text_syms == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no kernel text symbols in {}", path.display())));
+    }
+
+    Ok(btree)
+}
+
+/// Which of the kernel functions `names` are in /proc/kallsyms, e.g. because their module is loaded.
+/// Only the names are read, so this works even if the addresses are hidden.
+pub fn find_kernel_functions<'a>(names: &[&'a str]) -> io::Result<BTreeSet<&'a str>> {
+    let f = BufReader::new(File::open("/proc/kallsyms")?);
+    let mut found = BTreeSet::new();
+
+    for line in f.lines() {
+        let line = line?;
+        let mut parts = line.split_ascii_whitespace();
+        if let (Some(ty), Some(name)) = (parts.nth(1), parts.next()) {
+            if let Some(name) = names.iter().find(|&&n| n == name && ty.eq_ignore_ascii_case("t")) {
+                found.insert(*name);
+            }
+        }
     }
+
+    Ok(found)
 }
 
-impl Add for Counts {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            net_rx_action:             self.net_rx_action             + rhs.net_rx_action,
-            __napi_poll:               self.__napi_poll               + rhs.__napi_poll,
-            netif_receive_skb:         self.netif_receive_skb         + rhs.netif_receive_skb,
-            do_xdp_generic:            self.do_xdp_generic            + rhs.do_xdp_generic,
-            tcf_classify:              self.tcf_classify              + rhs.tcf_classify,
-            br_handle_frame:           self.br_handle_frame           + rhs.br_handle_frame,
-            netif_receive_skb_sub_br:  self.netif_receive_skb_sub_br  + rhs.netif_receive_skb_sub_br,
-            ip_forward:                self.ip_forward                + rhs.ip_forward,
-            ip6_forward:               self.ip6_forward               + rhs.ip6_forward,
-            ip_local_deliver:          self.ip_local_deliver          + rhs.ip_local_deliver,
-            ip6_input:                 self.ip6_input                 + rhs.ip6_input,
-            nf_netdev_ingress:         self.nf_netdev_ingress         + rhs.nf_netdev_ingress,
-            nf_prerouting_v4:          self.nf_prerouting_v4          + rhs.nf_prerouting_v4,
-            nf_prerouting_v6:          self.nf_prerouting_v6          + rhs.nf_prerouting_v6,
-            napi_gro_receive_overhead: self.napi_gro_receive_overhead + rhs.napi_gro_receive_overhead,
-            nf_conntrack_in:           self.nf_conntrack_in           + rhs.nf_conntrack_in
-            // nf_local_in_v4:           self.nf_local_in_v4           + rhs.nf_local_in_v4,
-            // nf_local_in_v6:           self.nf_local_in_v6           + rhs.nf_local_in_v6,
-            // nf_forward_v4:            self.nf_forward_v4            + rhs.nf_forward_v4,
-            // nf_forward_v6:            self.nf_forward_v6            + rhs.nf_forward_v6
+/// Whether the first parameter of the kernel function `name` is a `void*`, according to the BTF
+/// of the kernel or, if not there, of `module`. None if the function is found in neither.
+pub fn first_param_is_void_ptr(name: &str, module: &str) -> io::Result<Option<bool>> {
+    let name = CString::new(name)?;
+    let module = CString::new(module)?;
+
+    unsafe {
+        let vmlinux = libbpf_sys::btf__load_vmlinux_btf();
+        if vmlinux.is_null() {
+            return Err(io::Error::last_os_error());
         }
+
+        let mut res = btf_first_param_is_void_ptr(vmlinux, &name);
+        if res.is_none() {
+            let module_btf = libbpf_sys::btf__load_module_btf(module.as_ptr(), vmlinux);
+            if !module_btf.is_null() {
+                res = btf_first_param_is_void_ptr(module_btf, &name);
+                libbpf_sys::btf__free(module_btf);
+            }
+        }
+        libbpf_sys::btf__free(vmlinux);
+
+        Ok(res)
     }
 }
 
-impl AddAssign for Counts {
-    fn add_assign(&mut self, rhs: Self) {
-        *self = *self + rhs;
+/// Whether the first parameter of the function `name` in `btf` is a `void*`, None if not found
+unsafe fn btf_first_param_is_void_ptr(btf: *const libbpf_sys::btf, name: &CStr) -> Option<bool> {
+    // The kind is in bits 24-28 of the info of a type, and the number of parameters in bits 0-15
+    let kind = |ty: *const libbpf_sys::btf_type| (*ty).info >> 24 & 0x1F;
+
+    let id = libbpf_sys::btf__find_by_name_kind(btf, name.as_ptr(), libbpf_sys::BTF_KIND_FUNC);
+    if id < 0 {
+        return None;
+    }
+
+    let proto = libbpf_sys::btf__type_by_id(btf, (*libbpf_sys::btf__type_by_id(btf, id as _)).__bindgen_anon_1.type_);
+    if proto.is_null() || kind(proto) != libbpf_sys::BTF_KIND_FUNC_PROTO || (*proto).info & 0xFFFF == 0 {
+        return Some(false);
+    }
+
+    // The parameters follow the prototype
+    let param = *(proto.add(1) as *const libbpf_sys::btf_param);
+    let ty = libbpf_sys::btf__type_by_id(btf, param.type_);
+
+    // Type 0 is void
+    Some(!ty.is_null() && kind(ty) == libbpf_sys::BTF_KIND_PTR && (*ty).__bindgen_anon_1.type_ == 0)
+}
+
+/// Names and load addresses of the kernel modules in /proc/modules
+fn read_modules() -> io::Result<Vec<(String, String)>> {
+    let f = BufReader::new(File::open("/proc/modules")?);
+    let mut modules = vec![];
+
+    for line in f.lines() {
+        let line = line?;
+        let parts = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        // Only the name and address are relevant, the other fields change with the module's use
+        if let (Some(name), Some(addr)) = (parts.first(), parts.get(5)) {
+            modules.push((name.to_string(), addr.to_string()));
+        }
     }
+
+    Ok(modules)
+}
+
+/// A symbol's range ends where the next one begins
+fn range_end<V>(btree: &BTreeMap<u64, V>, range_start: u64) -> u64 {
+    btree
+        .range(range_start+1..)
+        .next()
+        .map(|(&addr, _)| addr)
+        .unwrap_or(range_start + 1)
 }
 
-impl Sum for Counts {
-    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
-        iter.reduce(|acc, e| acc + e).unwrap_or_default()
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::{categories, common::event_types_EVENT_NET_RX_SOFTIRQ};
+    use super::*;
+
+    /// Symbolized RX softirq traces, in the format described at the top of the file
+    const RX_SOFTIRQ_TRACES: &str = include_str!("../tests/traces/rx_softirq.txt");
+
+    /// Address of the first symbol of the symbol tables made up for the traces
+    const TEXT_START: u64 = 0xffff_ffff_8100_0000;
+
+    /// Frames of the traces in `contents`, as (name, module) pairs
+    fn parse_frames(contents: &str) -> Vec<Vec<(&str, Option<&str>)>> {
+        contents
+            .split("\n\n")
+            .map(|block| block
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with(['#', '=', '@']))
+                .map(|line| match line.split_once(' ') {
+                    Some((name, module)) => (name, module.strip_prefix('[').and_then(|m| m.strip_suffix(']'))),
+                    None => (line, None)
+                })
+                .collect::<Vec<_>>()
+            )
+            .filter(|frames| !frames.is_empty())
+            .collect()
+    }
+
+    /// Ranges of the symbols of interest of `breakdown`, as (end, rule) keyed by start,
+    /// built the way they were before `SymTable`
+    fn btree_syms(breakdown: &Breakdown, btree: &BTreeMap<u64, KSym>) -> BTreeMap<u64, (u64, usize)> {
+        let mut syms = BTreeMap::<u64, (u64, usize)>::new();
+        let mut last = None;
+
+        for (&range_start, ksym) in btree {
+            let Some(rule) = breakdown.rule(&ksym.name, ksym.module.as_deref()) else {
+                last = None;
+                continue;
+            };
+            let end = range_end(btree, range_start);
+
+            match last.and_then(|start| syms.get_mut(&start)) {
+                Some((range_end, fun)) if *fun == rule => *range_end = end,
+                _ => {
+                    syms.insert(range_start, (end, rule));
+                    last = Some(range_start);
+                }
+            }
+        }
+
+        syms
+    }
+
+    /// Look up `ip` in `syms` the way it was before `SymTable`
+    fn btree_lookup(syms: &BTreeMap<u64, (u64, usize)>, ip: u64) -> Option<usize> {
+        syms
+            .range(..=ip)
+            .next_back()
+            .and_then(|(_, &(range_end, rule))| (ip < range_end).then_some(rule))
+    }
+
+    #[test]
+    fn sym_tables_match_btree_lookup() {
+        let traces = parse_frames(RX_SOFTIRQ_TRACES);
+        assert!(!traces.is_empty());
+
+        // Lay out the symbols of the traces in a symbol table, every other one followed
+        // by an unrelated symbol so that some ranges of the same rule are merged
+        let mut addrs = HashMap::new();
+        let path = std::env::temp_dir().join(format!("netto-kallsyms-{}", std::process::id()));
+        let mut f = File::create(&path).unwrap();
+        for &(name, module) in traces.iter().flatten() {
+            if addrs.contains_key(&(name, module)) {
+                continue;
+            }
+            let idx = addrs.len() as u64;
+            let addr = TEXT_START + idx * 0x1000;
+            addrs.insert((name, module), addr);
+
+            match module {
+                Some(module) => writeln!(f, "{addr:016x} t {name} [{module}]").unwrap(),
+                None => writeln!(f, "{addr:016x} T {name}").unwrap()
+            }
+            if idx % 2 == 0 {
+                writeln!(f, "{:016x} t netto_test_gap_{idx}", addr + 0x800).unwrap();
+            }
+        }
+        drop(f);
+
+        let mut ksyms = KSyms {
+            breakdowns: categories::load(None).unwrap(),
+            kallsyms: Some(path.clone()),
+            ..Default::default()
+        };
+        ksyms.load_syms().unwrap();
+        let btree = read_kallsyms(Some(&path), 0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Frames land at different offsets in their function in each trace
+        let traces = traces
+            .iter()
+            .enumerate()
+            .map(|(trace_idx, frames)| frames
+                .iter()
+                .map(|frame| addrs[frame] + (trace_idx as u64 % 7) * 0x30)
+                .collect::<Vec<_>>()
+            )
+            .collect::<Vec<_>>();
+
+        let mut ip_cache = IpCache::default();
+        for (breakdown_idx, breakdown) in ksyms.breakdowns().iter().enumerate() {
+            let syms = btree_syms(breakdown, &btree);
+            let mut btree_counts = breakdown.counts();
+            let mut counts = breakdown.counts();
+
+            // The second pass hits the cache
+            for _ in 0..2 {
+                for trace in &traces {
+                    let mut btree_marks = 0;
+                    for &ip in trace {
+                        if let Some(rule) = btree_lookup(&syms, ip) {
+                            run_actions(breakdown.actions(rule), &mut btree_marks);
+                        }
+                    }
+                    btree_counts.add(breakdown.finish(event_types_EVENT_NET_RX_SOFTIRQ, btree_marks));
+
+                    let marks = unsafe { ksyms.classify(
+                        breakdown_idx,
+                        &mut ip_cache,
+                        trace.as_ptr(),
+                        trace.len(),
+                        #[cfg(feature = "save-traces")]
+                        std::io::sink()
+                    ) };
+                    counts.add(breakdown.finish(event_types_EVENT_NET_RX_SOFTIRQ, marks));
+                }
+            }
+
+            assert_eq!(counts, btree_counts, "breakdown {breakdown_idx}");
+        }
     }
 }
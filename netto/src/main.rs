@@ -4,19 +4,22 @@ mod bpf {
 #[allow(warnings)]
 mod common;
 mod ksyms;
+mod categories;
+mod cgroups;
+mod irqs;
 mod actors;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, collections::BTreeSet};
 use actix::Actor;
 use actix_files::Files;
 use actix_web::{HttpServer, App, rt::System, web};
-use actors::trace_analyzer::TraceAnalyzer;
+use actors::trace_analyzer::{TraceAnalyzer, TraceAnalyzerConfig};
 use anyhow::anyhow;
 use clap::Parser;
 use libbpf_rs::num_possible_cpus;
 use perf_event_open_sys::{bindings::{perf_event_attr, PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK}, perf_event_open};
 use tokio::sync::{mpsc::channel, watch};
-use crate::actors::{metrics_collector::MetricsCollector, websocket_client::ws_get, file_logger::FileLogger, prometheus_logger::{PrometheusLogger, prometheus_log_get}};
+use crate::{actors::{metrics_collector::MetricsCollector, websocket_client::ws_get, file_logger::FileLogger, prometheus_logger::{PrometheusLogger, prometheus_log_get}}, common::STACK_TRACE_ENTRY_LEN};
 
 #[derive(Parser)]
 #[command(name = "netto")]
@@ -24,7 +27,7 @@ use crate::actors::{metrics_collector::MetricsCollector, websocket_client::ws_ge
 #[command(about = "eBPF-based network diagnosis tool for Linux")]
 #[command(version)]
 struct Cli {
-    /// Perf-event's sampling frequency in Hz for the NET_RX_SOFTIRQ cost breakdown
+    /// Perf-event's sampling frequency in Hz for the NET_RX_SOFTIRQ, NET_TX_SOFTIRQ, RX busy poll, RX threaded NAPI and TX syscalls cost breakdowns
     #[arg(short, long, default_value_t = 1000)]
     frequency: u64,
 
@@ -40,6 +43,48 @@ struct Cli {
     #[arg(long, default_value_t = 500)]
     user_period: u64,
 
+    /// Number of processes with the highest networking syscall cost to report in each update
+    #[arg(long, default_value_t = 10)]
+    top_processes: usize,
+
+    /// Grow the stack traces buffer when samples keep being dropped because the user-space controller is late
+    #[arg(long, default_value_t = false)]
+    grow_stack_traces: bool,
+
+    /// Send the stack traces through a BPF ring buffer rather than the double-buffered array
+    #[arg(long, default_value_t = false, conflicts_with = "classify_in_kernel")]
+    ringbuf: bool,
+
+    /// Classify the stack traces in-kernel and only read per-category counters from user-space,
+    /// reducing the user-space overhead at the cost of the per-device and per-XDP-program breakdowns
+    #[arg(long, default_value_t = false)]
+    classify_in_kernel: bool,
+
+    /// Path to a TOML file defining the categories the stack traces are broken down into,
+    /// in place of the built-in ones (see categories.toml for the format)
+    #[arg(long, value_name = "FILE")]
+    categories: Option<PathBuf>,
+
+    /// Path to a symbol table of the running kernel in the /proc/kallsyms format (e.g. its System.map)
+    /// to use in place of /proc/kallsyms, for when its addresses are hidden. Relocated by the KASLR
+    /// offset of the running kernel (Linux 5.15+)
+    #[arg(long, value_name = "FILE")]
+    kallsyms: Option<PathBuf>,
+
+    /// Periodically print the given number of kernel symbols found most often at the top
+    /// of the stack traces that fall in none of the known categories ("Other")
+    #[arg(long, value_name = "SYMBOLS", conflicts_with = "classify_in_kernel")]
+    debug_unclassified: Option<usize>,
+
+    /// Number of threads the stack traces are analyzed by, each taking the traces of a range of CPUs
+    #[arg(long, value_name = "THREADS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    trace_workers: u32,
+
+    /// Break down the netfilter time by nftables and iptables table and chain, reporting at most the
+    /// given number of chains, in the order they are first sampled, and any other chain as "Other"
+    #[arg(long, value_name = "CHAINS", default_value_t = 0, conflicts_with = "classify_in_kernel")]
+    nf_chains: usize,
+
     /// Path to a log file to which measurements are to be saved.
     /// If logging is enabled by providing this argument, any other form of web interface will be disabled.
     #[arg(short, long)]
@@ -53,6 +98,7 @@ struct Cli {
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let breakdowns = categories::load(cli.categories.as_deref())?;
     
     System::new().block_on(async {
         let num_possible_cpus = num_possible_cpus()?;
@@ -60,16 +106,101 @@ fn main() -> anyhow::Result<()> {
         // Init BPF: open the libbpf skeleton, load the progs and attach them
         let mut open_skel = bpf::ProgSkelBuilder::default().open()?;
 
-        let stack_traces_max_entries = (cli.frequency as f64 *
-            num_possible_cpus as f64 *
+        open_skel.rodata().classify_in_kernel = cli.classify_in_kernel;
+        open_skel.rodata().use_ringbuf = cli.ringbuf;
+
+        // The cgroups are only resolved in the unified hierarchy
+        open_skel.rodata().account_cgroups = cgroups::unified_hierarchy();
+        if !open_skel.rodata().account_cgroups {
+            println!("Per-cgroup breakdown disabled: the unified (v2) cgroup hierarchy is not mounted at /sys/fs/cgroup");
+        }
+
+        // The netfilter chains can only be tracked through the modules loaded at startup
+        let nf_chain_funcs = if cli.nf_chains > 0 {
+            ksyms::find_kernel_functions(&["nft_do_chain", "ipt_do_table", "ip6t_do_table"])?
+        } else {
+            BTreeSet::new()
+        };
+        if cli.nf_chains > 0 && nf_chain_funcs.is_empty() {
+            println!("Netfilter chains not tracked: neither nf_tables nor ip_tables or ip6_tables are loaded");
+        }
+
+        // ipt_do_table and ip6t_do_table take the table first since Linux 5.17, and last before
+        let ipt_table_first = nf_chain_funcs.contains("ipt_do_table") &&
+            ksyms::first_param_is_void_ptr("ipt_do_table", "ip_tables")?.unwrap_or(true);
+        let ipt_table_last = nf_chain_funcs.contains("ipt_do_table") && !ipt_table_first;
+        let ip6t_table_first = nf_chain_funcs.contains("ip6t_do_table") &&
+            ksyms::first_param_is_void_ptr("ip6t_do_table", "ip6_tables")?.unwrap_or(true);
+        let ip6t_table_last = nf_chain_funcs.contains("ip6t_do_table") && !ip6t_table_first;
+
+        open_skel.progs_mut().nft_do_chain_entry().set_autoload(nf_chain_funcs.contains("nft_do_chain"))?;
+        open_skel.progs_mut().nft_do_chain_exit().set_autoload(nf_chain_funcs.contains("nft_do_chain"))?;
+        open_skel.progs_mut().ipt_do_table_entry().set_autoload(ipt_table_first)?;
+        open_skel.progs_mut().ipt_do_table_legacy_entry().set_autoload(ipt_table_last)?;
+        open_skel.progs_mut().ipt_do_table_exit().set_autoload(nf_chain_funcs.contains("ipt_do_table"))?;
+        open_skel.progs_mut().ip6t_do_table_entry().set_autoload(ip6t_table_first)?;
+        open_skel.progs_mut().ip6t_do_table_legacy_entry().set_autoload(ip6t_table_last)?;
+        open_skel.progs_mut().ip6t_do_table_exit().set_autoload(nf_chain_funcs.contains("ip6t_do_table"))?;
+
+        // napi_busy_loop is missing when the kernel is built without CONFIG_NET_RX_BUSY_POLL
+        let busy_poll = !ksyms::find_kernel_functions(&["napi_busy_loop"])?.is_empty();
+        if !busy_poll {
+            println!("RX busy poll not tracked: napi_busy_loop not found in the kernel symbols");
+        }
+        open_skel.progs_mut().napi_busy_loop_entry().set_autoload(busy_poll)?;
+        open_skel.progs_mut().napi_busy_loop_exit().set_autoload(busy_poll)?;
+
+        // Hardware interrupts end in irq_exit_rcu on architectures with generic entry code, and in irq_exit elsewhere
+        let irq_exit_funcs = ksyms::find_kernel_functions(&["irq_exit_rcu", "irq_exit"])?;
+        if irq_exit_funcs.is_empty() {
+            return Err(anyhow!("Neither irq_exit_rcu nor irq_exit found in the kernel symbols"));
+        }
+        open_skel.progs_mut().irq_exit_rcu_entry().set_autoload(irq_exit_funcs.contains("irq_exit_rcu"))?;
+        open_skel.progs_mut().irq_exit_rcu_exit().set_autoload(irq_exit_funcs.contains("irq_exit_rcu"))?;
+        open_skel.progs_mut().irq_exit_entry().set_autoload(irq_exit_funcs.contains("irq_exit"))?;
+        open_skel.progs_mut().irq_exit_exit().set_autoload(irq_exit_funcs.contains("irq_exit"))?;
+
+        // Maximum number of samples taken by each cpu in a user-space period
+        let samples_per_period = (cli.frequency as f64 *
             (cli.user_period as f64 / 1000.0) *
             1.1 // Add 10% margin to account for controller scheduling irregularities
-        ).ceil() as u32 * 2;
-        println!("Allocated memory for stack traces BPF map: {}B", stack_traces_max_entries * 128 * 8);
-        open_skel.maps_mut().stack_traces().set_max_entries(stack_traces_max_entries)?;
+        ).ceil() as u32;
+
+        // Each cpu gets two slots of `stack_traces_slot_entries` entries.
+        // The buffer is left unused when the traces are classified in-kernel or sent through the ring buffer.
+        let stack_traces_slot_entries = if cli.classify_in_kernel || cli.ringbuf { 1 } else { samples_per_period };
+        println!(
+            "Allocated memory for stack traces BPF map: {}B",
+            stack_traces_slot_entries as u64 * 2 * num_possible_cpus as u64 * STACK_TRACE_ENTRY_LEN as u64 * 8
+        );
+        open_skel.maps_mut().stack_traces_count().set_max_entries(2 * num_possible_cpus as u32)?;
+        open_skel.maps_mut().stack_traces_dropped().set_max_entries(2 * num_possible_cpus as u32)?;
+
+        // The ring buffer size must be a power of 2 multiple of the page size
+        if cli.ringbuf {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+            let ringbuf_size = (samples_per_period as u64 * num_possible_cpus as u64 * STACK_TRACE_ENTRY_LEN as u64 * 8)
+                .next_power_of_two()
+                .max(page_size);
+            println!("Allocated memory for stack traces ring buffer: {ringbuf_size}B");
+            open_skel.maps_mut().stack_traces_ringbuf().set_max_entries(ringbuf_size as _)?;
+        }
+
+        // Only needed to relocate the symbol table passed in place of /proc/kallsyms
+        open_skel.progs_mut().kaslr_anchor().set_autoload(cli.kallsyms.is_some())?;
 
         let mut skel = open_skel.load()?;
 
+        // Find the runtime address of the KASLR anchor by briefly tracing it
+        let kallsyms = match cli.kallsyms.clone() {
+            Some(path) => {
+                let _kaslr_anchor_link = skel.progs_mut().kaslr_anchor().attach()?;
+                unsafe { libc::getpid() };
+                Some((path, skel.bss().kaslr_anchor_ip))
+            },
+            None => None
+        };
+
         // Explicitly attach entry programs last (because the task-local storage can only be allocated by them)
         #[cfg(not(feature = "save-traces"))]
         let _sched_switch_link = skel.progs_mut().tp_sched_switch().attach()?;
@@ -78,7 +209,27 @@ fn main() -> anyhow::Result<()> {
         #[cfg(not(feature = "save-traces"))]
         let _sock_recvmsg_exit_link = skel.progs_mut().sock_recvmsg_exit().attach()?;
         #[cfg(not(feature = "save-traces"))]
+        let _napi_busy_loop_exit_link = busy_poll.then(|| skel.progs_mut().napi_busy_loop_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
         let _net_rx_softirq_exit_link = skel.progs_mut().net_rx_softirq_exit().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _napi_poll_exit_link = skel.progs_mut().napi_poll_exit().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _net_irq_handler_exit_link = skel.progs_mut().net_irq_handler_exit().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _irq_exit_rcu_exit_link = irq_exit_funcs.contains("irq_exit_rcu").then(|| skel.progs_mut().irq_exit_rcu_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _irq_exit_exit_link = irq_exit_funcs.contains("irq_exit").then(|| skel.progs_mut().irq_exit_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _nf_hook_slow_exit_link = skel.progs_mut().nf_hook_slow_exit().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _nft_do_chain_exit_link = nf_chain_funcs.contains("nft_do_chain").then(|| skel.progs_mut().nft_do_chain_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ipt_do_table_exit_link = nf_chain_funcs.contains("ipt_do_table").then(|| skel.progs_mut().ipt_do_table_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ip6t_do_table_exit_link = nf_chain_funcs.contains("ip6t_do_table").then(|| skel.progs_mut().ip6t_do_table_exit().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _sched_wakeup_link = skel.progs_mut().tp_sched_wakeup().attach()?;
 
         // Open and attach a perf-event program for each CPU
         let _perf_event_links = unsafe {
@@ -126,7 +277,29 @@ fn main() -> anyhow::Result<()> {
         #[cfg(not(feature = "save-traces"))]
         let _sock_recvmsg_entry_link = skel.progs_mut().sock_recvmsg_entry().attach()?;
         #[cfg(not(feature = "save-traces"))]
+        let _napi_busy_loop_entry_link = busy_poll.then(|| skel.progs_mut().napi_busy_loop_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
         let _net_rx_softirq_entry_link = skel.progs_mut().net_rx_softirq_entry().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _napi_poll_entry_link = skel.progs_mut().napi_poll_entry().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _net_irq_handler_entry_link = skel.progs_mut().net_irq_handler_entry().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _irq_exit_rcu_entry_link = irq_exit_funcs.contains("irq_exit_rcu").then(|| skel.progs_mut().irq_exit_rcu_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _irq_exit_entry_link = irq_exit_funcs.contains("irq_exit").then(|| skel.progs_mut().irq_exit_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _nf_hook_slow_entry_link = skel.progs_mut().nf_hook_slow_entry().attach()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _nft_do_chain_entry_link = nf_chain_funcs.contains("nft_do_chain").then(|| skel.progs_mut().nft_do_chain_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ipt_do_table_entry_link = ipt_table_first.then(|| skel.progs_mut().ipt_do_table_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ipt_do_table_legacy_entry_link = ipt_table_last.then(|| skel.progs_mut().ipt_do_table_legacy_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ip6t_do_table_entry_link = ip6t_table_first.then(|| skel.progs_mut().ip6t_do_table_entry().attach()).transpose()?;
+        #[cfg(not(feature = "save-traces"))]
+        let _ip6t_do_table_legacy_entry_link = ip6t_table_last.then(|| skel.progs_mut().ip6t_do_table_legacy_entry().attach()).transpose()?;
 
         // Init actors
         let (error_catcher_sender, mut error_catcher_receiver) =
@@ -150,11 +323,23 @@ fn main() -> anyhow::Result<()> {
             prometheus_logger_addr.as_ref().map(|(_, l)| l.to_owned())
         ).start();
         
+        let trace_analyzer_config = TraceAnalyzerConfig {
+            run_interval_ms: cli.user_period,
+            stack_traces_slot_entries,
+            grow_stack_traces: cli.grow_stack_traces,
+            use_ringbuf: cli.ringbuf,
+            classify_in_kernel: cli.classify_in_kernel,
+            breakdowns,
+            kallsyms,
+            debug_unclassified: cli.debug_unclassified,
+            trace_workers: cli.trace_workers as _,
+            nf_chains_max: cli.nf_chains,
+            top_processes: cli.top_processes
+        };
         let _trace_analyzer_actor_addr = TraceAnalyzer::new(
-            cli.user_period,
+            trace_analyzer_config,
             skel,
             num_possible_cpus,
-            stack_traces_max_entries,
             metrics_collector_actor_addr.clone(),
             error_catcher_sender
         )?.start();
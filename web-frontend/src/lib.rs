@@ -5,7 +5,7 @@ use std::rc::Rc;
 
 use metrics_common::MetricsWrapper;
 use plot::update_plot;
-use table::build_table;
+use table::{build_table, build_dimension_table, build_processes_table};
 use wasm_bindgen::prelude::*;
 use web_sys::{console, WebSocket, MessageEvent};
 use plotters::{prelude::*, coord::types::RangedCoordf32};
@@ -24,7 +24,13 @@ pub fn main() -> Result<(), JsValue> {
     let table = Rc::new(document.query_selector("#metrics-table")?.expect("Failed to find main table element in document"));
     let overhead_element = Rc::new(document.query_selector("#overhead")?.expect("Failed to find user-space overhead element in document"));
     let power_element = Rc::new(document.query_selector("#power")?.expect("Failed to find power draw element in document"));
+    let deferrals_element = Rc::new(document.query_selector("#softirq-deferrals")?.expect("Failed to find softirq deferrals element in document"));
+    let dropped_samples_element = Rc::new(document.query_selector("#dropped-samples")?.expect("Failed to find dropped samples element in document"));
+    let breakdowns_element = Rc::new(document.query_selector("#breakdowns")?.expect("Failed to find breakdowns element in document"));
     let procfs_table = Rc::new(document.query_selector("#procfs-table")?.expect("Failed to find procfs table in document"));
+    let cgroups_table = Rc::new(document.query_selector("#cgroups-table")?.expect("Failed to find cgroups table in document"));
+    let interfaces_table = Rc::new(document.query_selector("#interfaces-table")?.expect("Failed to find interfaces table in document"));
+    let processes_table = Rc::new(document.query_selector("#processes-table")?.expect("Failed to find processes table in document"));
     let svg_container = Rc::new(document.query_selector("#svg-container")?.expect("Failed to find svg container in document"));
 
     let ws = WebSocket::new(&(window.location().origin()?.replace("http", "ws") + "/ws/"))?;
@@ -52,7 +58,13 @@ pub fn main() -> Result<(), JsValue> {
             let table = Rc::clone(&table);
             let overhead_element = Rc::clone(&overhead_element);
             let power_element = Rc::clone(&power_element);
+            let deferrals_element = Rc::clone(&deferrals_element);
+            let dropped_samples_element = Rc::clone(&dropped_samples_element);
+            let breakdowns_element = Rc::clone(&breakdowns_element);
             let procfs_table = Rc::clone(&procfs_table);
+            let cgroups_table = Rc::clone(&cgroups_table);
+            let interfaces_table = Rc::clone(&interfaces_table);
+            let processes_table = Rc::clone(&processes_table);
             let svg_container = Rc::clone(&svg_container);
             
             let fr = gloo_file::callbacks::read_as_bytes(&blob, move |res| {
@@ -66,6 +78,15 @@ pub fn main() -> Result<(), JsValue> {
                             while let Some(child) = procfs_table.last_child() {
                                 procfs_table.remove_child(&child)?;
                             }
+                            while let Some(child) = cgroups_table.last_child() {
+                                cgroups_table.remove_child(&child)?;
+                            }
+                            while let Some(child) = interfaces_table.last_child() {
+                                interfaces_table.remove_child(&child)?;
+                            }
+                            while let Some(child) = processes_table.last_child() {
+                                processes_table.remove_child(&child)?;
+                            }
                             
                             // Update procfs metrics
                             for (i, procfs_metric) in metrics.procfs_metrics.iter().enumerate() {
@@ -91,6 +112,15 @@ pub fn main() -> Result<(), JsValue> {
                                     "  N/A".to_string()
                                 }
                             ));
+                            deferrals_element.set_text_content(Some(&format!("{: >6}", metrics.softirq_deferrals)));
+                            dropped_samples_element.set_text_content(Some(&format!("{: >6}", metrics.dropped_samples)));
+                            breakdowns_element.set_text_content(Some(&
+                                if let Some(reason) = &metrics.breakdowns_unavailable {
+                                    format!("Unavailable: {reason}")
+                                } else {
+                                    "Available".to_string()
+                                }
+                            ));
 
                             // Update plot
                             let mut svg_buf = String::new();
@@ -105,6 +135,13 @@ pub fn main() -> Result<(), JsValue> {
                             }
                             svg_container.set_inner_html(&svg_buf);
 
+                            // Update per-cgroup and per-interface metrics tables
+                            build_dimension_table(&document, &cgroups_table, &metrics.cgroup_metrics, metrics.num_possible_cpus)?;
+                            build_dimension_table(&document, &interfaces_table, &metrics.interface_metrics, metrics.num_possible_cpus)?;
+
+                            // Update top processes table
+                            build_processes_table(&document, &processes_table, &metrics)?;
+
                             // Update main metrics table
                             build_table(&document, &table, metrics)?;
                             
@@ -140,12 +140,15 @@ fn draw_bar1<DB: DrawingBackend>(
     let kernel_adjusted = kernel.max(networking);
     
     let colors = [
-        (palette::ORANGE_400.into(), BLACK.into()), // TX syscalls
-        (palette::GREEN_800.into(), WHITE.into()),  // RX syscalls
-        (CYAN.into(), BLACK.into()),                // TX softirq
-        (palette::PURPLE.into(), WHITE.into()),     // RX softirq
-        (palette::PINK_A200.into(), WHITE.into()),  // IO workers
-        (BLACK.mix(0.2), BLACK.into())              // other
+        (palette::ORANGE_400.into(), BLACK.into()),     // TX syscalls
+        (palette::GREEN_800.into(), WHITE.into()),      // RX syscalls
+        (CYAN.into(), BLACK.into()),                    // TX softirq
+        (palette::PURPLE.into(), WHITE.into()),         // RX softirq
+        (palette::PINK_A200.into(), WHITE.into()),      // IO workers
+        (palette::YELLOW_700.into(), BLACK.into()),     // HW IRQ
+        (palette::TEAL_400.into(), WHITE.into()),       // RX busy poll
+        (palette::DEEPPURPLE_200.into(), BLACK.into()), // RX threaded NAPI
+        (BLACK.mix(0.2), BLACK.into())                  // other
     ];
     let stack = metrics.top_level_metrics
         .iter()
@@ -196,13 +199,23 @@ fn draw_bar2<DB: DrawingBackend>(
     let colors = [
         (palette::LIGHTGREEN_A700.into(), BLACK.into()), // Driver poll
         (palette::INDIGO_A200.into(), WHITE.into()),     // GRO overhead
+        (palette::LIME_A400.into(), BLACK.into()),       // XDP
         (palette::PINK_A100.into(), BLACK.into()),       // XDP generic
         (palette::BLUEGREY.into(), WHITE.into()),        // TC classify
         (palette::AMBER.into(), BLACK.into()),           // NF ingress
+        (palette::AMBER_800.into(), BLACK.into()),       // NF egress
         (palette::GREEN_200.into(), BLACK.into()),       // NF conntrack
         (palette::DEEPPURPLE.into(), WHITE.into()),      // Bridging
         (palette::BROWN_200.into(), BLACK.into()),       // NF prerouting/v4
         (palette::BROWN_A700.into(), WHITE.into()),      // NF prerouting/v6
+        (palette::ORANGE_200.into(), BLACK.into()),      // NF local in/v4
+        (palette::ORANGE_A700.into(), BLACK.into()),     // NF local in/v6
+        (palette::TEAL_200.into(), BLACK.into()),        // NF forward/v4
+        (palette::TEAL_A700.into(), WHITE.into()),       // NF forward/v6
+        (palette::CYAN_200.into(), BLACK.into()),        // NF local out/v4
+        (palette::CYAN_A700.into(), BLACK.into()),       // NF local out/v6
+        (palette::PURPLE_200.into(), BLACK.into()),      // NF postrouting/v4
+        (palette::PURPLE_A700.into(), WHITE.into()),     // NF postrouting/v6
         (palette::BLUE_200.into(), BLACK.into()),        // Forwarding/v4
         (palette::BLUE_A700.into(), WHITE.into()),       // Forwarding/v6
         (palette::RED_200.into(), BLACK.into()),         // Local delivery/v4
@@ -211,6 +224,9 @@ fn draw_bar2<DB: DrawingBackend>(
     ];
     let sub_metrics = rx_softirq_metric.sub_metrics
         .iter()
+        // The execution contexts and the netfilter chains are orthogonal breakdowns of the same
+        // time, and the unclassified time is already accounted as the remainder below
+        .filter(|s| s.name != "Context" && s.name != "Netfilter" && s.name != "Other")
         .flat_map(|s| if s.cpu_fracs.len() == metrics.num_possible_cpus {
             vec![(s.name.clone(), s.cpu_fracs.iter().sum::<f64>() / metrics.num_possible_cpus as f64)]
         } else {
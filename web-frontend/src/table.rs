@@ -1,4 +1,4 @@
-use metrics_common::{Metric, MetricsWrapper};
+use metrics_common::{Metric, MetricsWrapper, SampleStats};
 use wasm_bindgen::JsValue;
 use web_sys::{Document, Element};
 
@@ -48,8 +48,22 @@ fn build_empty_row(document: &Document, num_possible_cpus: usize) -> Result<Elem
     Ok(row)
 }
 
+/// Small annotation with the number of samples a value is based on and its 95% confidence interval
 #[inline]
-fn build_values_row(document: &Document, prefix: &str, name: &str, values: &[f64], num_possible_cpus: usize) -> Result<Element, JsValue> {
+fn build_samples_annotation(document: &Document, samples: &SampleStats) -> Result<Element, JsValue> {
+    let annotation = document.create_element("small")?;
+    annotation.set_text_content(Some(&format!(
+        " n={} [{:.02}, {:.02}]",
+        samples.count,
+        samples.ci_lower * 100.0,
+        samples.ci_upper * 100.0
+    )));
+    annotation.set_attribute("title", "Number of samples and 95% confidence interval")?;
+    Ok(annotation)
+}
+
+#[inline]
+fn build_values_row(document: &Document, prefix: &str, name: &str, values: &[f64], samples: &[Option<SampleStats>], num_possible_cpus: usize) -> Result<Element, JsValue> {
     let row = document.create_element("tr")?;
 
     let name_cell = document.create_element("th")?;
@@ -60,9 +74,14 @@ fn build_values_row(document: &Document, prefix: &str, name: &str, values: &[f64
 
     if values.len() == num_possible_cpus {
         let mut cumulative = 0.0;
-        for v in values {
+        let mut cumulative_samples = None;
+        for (cpuid, v) in values.iter().enumerate() {
             let value_cell = document.create_element("td")?;
             value_cell.set_text_content(Some(&format!("{: >8.02}%", v * 100.0)));
+            if let Some(Some(s)) = samples.get(cpuid) {
+                value_cell.append_child(&build_samples_annotation(document, s)?)?;
+                *cumulative_samples.get_or_insert(0) += s.count;
+            }
             row.append_child(&value_cell)?;
 
             cumulative += *v;
@@ -73,6 +92,11 @@ fn build_values_row(document: &Document, prefix: &str, name: &str, values: &[f64
 
         let cumulative_cell = document.create_element("td")?;
         cumulative_cell.set_text_content(Some(&format!("{: >8.02}%", cumulative * 100.0)));
+        if let Some(count) = cumulative_samples {
+            let annotation = document.create_element("small")?;
+            annotation.set_text_content(Some(&format!(" n={count}")));
+            cumulative_cell.append_child(&annotation)?;
+        }
         row.append_child(&cumulative_cell)?;
     } else {
         for _ in 0..num_possible_cpus + 2 {
@@ -98,6 +122,7 @@ fn append_metric_row(
             prefix,
             &metric.name,
             &metric.cpu_fracs,
+            &metric.samples,
             num_possible_cpus
         )?.into()
     )?;
@@ -166,9 +191,79 @@ pub fn build_table(document: &Document, table: &Element, metrics: MetricsWrapper
             "",
             "TOTAL",
             &total_values,
+            &[],
             metrics.num_possible_cpus
         )?.into())?;
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Build a table for a secondary dimension of the metrics (e.g., cgroups), with one tree for each root
+#[inline]
+pub fn build_dimension_table(document: &Document, table: &Element, roots: &[Metric], num_possible_cpus: usize) -> Result<(), JsValue> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+    
+    table.append_child(&build_table_header(
+        document,
+        num_possible_cpus
+    )?.into())?;
+
+    table.append_child(&build_empty_row(
+        document,
+        num_possible_cpus
+    )?.into())?;
+
+    for root in roots {
+        append_metric_row(
+            document,
+            "",
+            "",
+            table,
+            root,
+            num_possible_cpus
+        )?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+pub fn build_processes_table(document: &Document, table: &Element, metrics: &MetricsWrapper) -> Result<(), JsValue> {
+    if metrics.top_processes.is_empty() {
+        return Ok(());
+    }
+
+    let header = document.create_element("tr")?;
+    for title in ["PID", "Command", "TX syscalls", "RX syscalls"] {
+        let cell = document.create_element("th")?;
+        cell.set_text_content(Some(title));
+        cell.set_attribute("style", "text-align: center")?;
+        header.append_child(&cell)?;
+    }
+    table.append_child(&header)?;
+
+    for process in &metrics.top_processes {
+        let row = document.create_element("tr")?;
+
+        let pid_cell = document.create_element("td")?;
+        pid_cell.set_text_content(Some(&format!("{: >8}", process.pid)));
+        row.append_child(&pid_cell)?;
+
+        let comm_cell = document.create_element("th")?;
+        comm_cell.set_text_content(Some(&process.comm));
+        row.append_child(&comm_cell)?;
+
+        for v in [process.tx_cpu_frac, process.rx_cpu_frac] {
+            let value_cell = document.create_element("td")?;
+            value_cell.set_text_content(Some(&format!("{: >8.02}%", v * 100.0)));
+            row.append_child(&value_cell)?;
+        }
+
+        table.append_child(&row)?;
+    }
+
+    Ok(())
+}